@@ -0,0 +1,172 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig,
+};
+
+use crate::io::sound::{StereoSample, SAMPLE_RATE};
+
+// About 3 video frames of audio are kept queued, emulation speed is nudged to stay near it
+const TARGET_BUFFERED_SECONDS: f64 = 0.05;
+const MAX_SPEED_ADJUSTMENT: f64 = 0.005;
+
+type SampleRing = Arc<Mutex<VecDeque<StereoSample>>>;
+
+// Plays the core's samples through a ring buffer drained by the audio device's callback
+pub struct AudioOutput {
+    _stream: Stream, // Playback stops once it's dropped
+    ring: SampleRing,
+    resampler: Resampler,
+    resampled: Vec<StereoSample>,
+    target_buffered_frames: usize,
+    pub volume: f32,
+    pub is_muted: bool,
+}
+
+impl AudioOutput {
+    // None when there is no audio device, emulation then runs silently on the wall clock
+    pub fn new(volume: f32) -> Option<AudioOutput> {
+        let ring: SampleRing = Arc::new(Mutex::new(VecDeque::new()));
+        let (stream, host_sample_rate) = match open_stream(Arc::clone(&ring)) {
+            Ok(stream) => stream,
+            Err(error) => {
+                println!("[Audio] {}, running without sound", error);
+                return None;
+            }
+        };
+
+        Some(AudioOutput {
+            _stream: stream,
+            ring,
+            resampler: Resampler::new(SAMPLE_RATE as f64 / host_sample_rate as f64),
+            resampled: Vec::new(),
+            target_buffered_frames: (host_sample_rate as f64 * TARGET_BUFFERED_SECONDS) as usize,
+            volume,
+            is_muted: false,
+        })
+    }
+
+    pub fn push_samples(&mut self, samples: &[StereoSample]) {
+        self.resampled.clear();
+        self.resampler.resample(samples, &mut self.resampled);
+
+        let gain = if self.is_muted { 0. } else { self.volume };
+        let apply_gain = |sample: f32| (sample * gain).clamp(-1., 1.);
+
+        let mut ring = self.ring.lock().unwrap();
+        for [left, right] in &self.resampled {
+            ring.push_back([apply_gain(*left), apply_gain(*right)]);
+        }
+
+        // Way ahead of the device, drop the oldest samples rather than adding latency
+        if ring.len() > self.target_buffered_frames * 4 {
+            let excess = ring.len() - self.target_buffered_frames;
+            ring.drain(..excess);
+        }
+    }
+
+    // Speed to run emulation at relative to the real hardware, slightly faster when the buffer
+    // runs low and slightly slower when it fills up
+    pub fn get_speed(&self) -> f64 {
+        let buffered = self.ring.lock().unwrap().len() as f64;
+        let target = self.target_buffered_frames as f64;
+        let adjustment = ((target - buffered) / target).clamp(-1., 1.);
+
+        1. + adjustment * MAX_SPEED_ADJUSTMENT
+    }
+}
+
+// Starts playback on the default output device at its own rate, returning the stream and the rate
+fn open_stream(ring: SampleRing) -> Result<(Stream, u32), String> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| String::from("No audio device"))?;
+    let supported_config = device
+        .default_output_config()
+        .map_err(|error| error.to_string())?;
+    let config = supported_config.config();
+
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, ring),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, ring),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, ring),
+        sample_format => return Err(format!("Unsupported sample format {}", sample_format)),
+    }
+    .map_err(|error| error.to_string())?;
+    stream.play().map_err(|error| error.to_string())?;
+
+    Ok((stream, config.sample_rate.0))
+}
+
+// Plays silence for whatever is missing when the buffer runs dry. Mono devices get both channels
+// mixed, channels past the first two are left silent
+fn build_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    ring: SampleRing,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            let mut ring = ring.lock().unwrap();
+
+            for frame in data.chunks_mut(channels) {
+                let [left, right] = ring.pop_front().unwrap_or([0., 0.]);
+
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = match (channels, channel) {
+                        (1, _) => (left + right) / 2.,
+                        (_, 0) => left,
+                        (_, 1) => right,
+                        _ => 0.,
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |error| println!("[Audio] {}", error),
+        None,
+    )
+}
+
+// Linear interpolation between the core's samples at the host's rate
+struct Resampler {
+    step: f64,     // Input samples per output sample
+    position: f64, // Between the previous and the next input sample
+    previous: StereoSample,
+}
+
+impl Resampler {
+    fn new(step: f64) -> Resampler {
+        Resampler {
+            step,
+            position: 0.,
+            previous: [0., 0.],
+        }
+    }
+
+    fn resample(&mut self, input: &[StereoSample], output: &mut Vec<StereoSample>) {
+        for sample in input {
+            while self.position < 1. {
+                let t = self.position as f32;
+                output.push([
+                    self.previous[0] + (sample[0] - self.previous[0]) * t,
+                    self.previous[1] + (sample[1] - self.previous[1]) * t,
+                ]);
+                self.position += self.step;
+            }
+
+            self.position -= 1.;
+            self.previous = *sample;
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use macroquad::prelude::*;
+
+pub const PANEL_WIDTH: f32 = 320.;
+
+const CHANNEL_NAMES: [&str; 4] = ["Pulse 1", "Pulse 2", "Wave", "Noise"];
+const MUTE_KEYS: [KeyCode; 4] = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4];
+const SOLO_KEYS: [KeyCode; 4] = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+// 0xFF15 and 0xFF1F aren't registers
+const REGISTER_NAMES: [Option<&str>; 0x17] = [
+    Some("NR10"),
+    Some("NR11"),
+    Some("NR12"),
+    Some("NR13"),
+    Some("NR14"),
+    None,
+    Some("NR21"),
+    Some("NR22"),
+    Some("NR23"),
+    Some("NR24"),
+    Some("NR30"),
+    Some("NR31"),
+    Some("NR32"),
+    Some("NR33"),
+    Some("NR34"),
+    None,
+    Some("NR41"),
+    Some("NR42"),
+    Some("NR43"),
+    Some("NR44"),
+    Some("NR50"),
+    Some("NR51"),
+    Some("NR52"),
+];
+
+const SCOPE_HEIGHT: f32 = 90.;
+const SCOPE_SPACING: f32 = 105.;
+const SCOPE_SAMPLES: usize = 400; // A little over 8 ms
+const SCOPE_RANGE: f32 = 0.5; // Channel samples go from -0.25 to 0.25, a bit more when filtered
+const MARGIN: f32 = 10.;
+const FONT_SIZE: f32 = 18.;
+
+// Mutes and solos channels, and shows each channel's waveform next to the game along with the
+// sound registers, for debugging sound drivers
+pub struct AudioDebugger {
+    pub is_visible: bool,
+    muted_channels: [bool; 4],
+    solo_channel: Option<usize>,
+    scopes: [Vec<f32>; 4],
+}
+
+impl AudioDebugger {
+    pub fn default() -> AudioDebugger {
+        AudioDebugger {
+            is_visible: false,
+            muted_channels: [false; 4],
+            solo_channel: None,
+            scopes: Default::default(),
+        }
+    }
+
+    // A soloed channel plays on its own, otherwise the muted channels are left out
+    pub fn get_muted_channels(&self) -> [bool; 4] {
+        std::array::from_fn(|index| match self.solo_channel {
+            Some(solo_channel) => index != solo_channel,
+            None => self.muted_channels[index],
+        })
+    }
+
+    // 1 to 4 mute a channel, F1 to F4 solo it. Returns true if the muted channels changed
+    pub fn handle_input(&mut self) -> bool {
+        let mut has_changed = false;
+
+        for index in 0..4 {
+            if is_key_pressed(MUTE_KEYS[index]) {
+                self.muted_channels[index] = !self.muted_channels[index];
+                has_changed = true;
+            }
+            if is_key_pressed(SOLO_KEYS[index]) {
+                self.solo_channel = match self.solo_channel {
+                    Some(solo_channel) if solo_channel == index => None,
+                    _ => Some(index),
+                };
+                has_changed = true;
+            }
+        }
+
+        has_changed
+    }
+
+    // Keeps the last frame's samples of each channel
+    pub fn update(&mut self, channel_samples: &[Vec<f32>; 4]) {
+        for (scope, samples) in self.scopes.iter_mut().zip(channel_samples) {
+            if !samples.is_empty() {
+                scope.clone_from(samples);
+            }
+        }
+    }
+
+    pub fn draw(&self, x: f32, registers: &[u8; 0x17]) {
+        draw_rectangle(
+            x,
+            0.,
+            PANEL_WIDTH,
+            screen_height(),
+            Color::new(0.1, 0.1, 0.1, 1.),
+        );
+
+        let muted_channels = self.get_muted_channels();
+        for (index, scope) in self.scopes.iter().enumerate() {
+            let y = MARGIN + index as f32 * SCOPE_SPACING;
+            let label = match (self.solo_channel, muted_channels[index]) {
+                (Some(solo_channel), _) if solo_channel == index => {
+                    format!("{} (solo)", CHANNEL_NAMES[index])
+                }
+                (_, true) => format!("{} (muted)", CHANNEL_NAMES[index]),
+                _ => String::from(CHANNEL_NAMES[index]),
+            };
+            let color = if muted_channels[index] { GRAY } else { GREEN };
+
+            draw_text(&label, x + MARGIN, y + FONT_SIZE * 0.75, FONT_SIZE, WHITE);
+            draw_scope(x + MARGIN, y + FONT_SIZE, scope, color);
+        }
+
+        // 4 registers a row
+        let registers_y = MARGIN + 4. * SCOPE_SPACING + FONT_SIZE;
+        let named_registers = REGISTER_NAMES
+            .iter()
+            .zip(registers)
+            .filter_map(|(name, value)| name.map(|name| (name, value)));
+
+        for (index, (name, value)) in named_registers.enumerate() {
+            let register_x = x + MARGIN + (index % 4) as f32 * 76.;
+            let register_y = registers_y + (index / 4) as f32 * (FONT_SIZE + 2.);
+            let text = format!("{} {:02X}", name, value);
+            draw_text(&text, register_x, register_y, FONT_SIZE, WHITE);
+        }
+    }
+}
+
+// Starts at the first rising edge through the middle of the waveform so it holds still
+fn draw_scope(x: f32, y: f32, samples: &[f32], color: Color) {
+    let width = PANEL_WIDTH - 2. * MARGIN;
+    let height = SCOPE_HEIGHT - FONT_SIZE;
+    let center_y = y + height / 2.;
+
+    draw_rectangle_lines(x, y, width, height, 1., DARKGRAY);
+
+    if samples.len() < 2 {
+        return;
+    }
+
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    let trigger = samples
+        .windows(2)
+        .take(samples.len().saturating_sub(SCOPE_SAMPLES))
+        .position(|pair| pair[0] < mean && pair[1] >= mean)
+        .unwrap_or(0);
+    let samples = &samples[trigger..samples.len().min(trigger + SCOPE_SAMPLES)];
+
+    let to_point = |index: usize, sample: f32| {
+        let sample = (sample / SCOPE_RANGE).clamp(-1., 1.);
+        (
+            x + index as f32 * width / SCOPE_SAMPLES as f32,
+            center_y - sample * height / 2.,
+        )
+    };
+
+    for (index, pair) in samples.windows(2).enumerate() {
+        let (x1, y1) = to_point(index, pair[0]);
+        let (x2, y2) = to_point(index + 1, pair[1]);
+        draw_line(x1, y1, x2, y2, 1., color);
+    }
+}
@@ -1,15 +1,26 @@
 use crate::{
-    cartridge::cartridge::Cartridge,
+    cartridge::{cartridge::Cartridge, gbs::GbsFile},
+    error::EmulationError,
     hram::HRam,
-    io::{io::IO, lcd::ScanLineEvent},
-    memory::MemoryLocation,
+    io::{
+        compatibility_palettes::{get_title_palettes, ManualPalette},
+        io::IO,
+        lcd::{ScanLineEvent, FRAME_DOTS},
+        serial::SERIAL_BIT_CYCLES,
+        sound::StereoSample,
+    },
+    memory::{MemoryBus, MemoryLocation},
     opcode::opcode::execute_opcode,
     ppu::PPU,
+    scheduler::{EventKind, Scheduler},
     wram::WRam,
 };
 
 use super::cpu::CPU;
 
+const SPEED_SWITCH_M_CYCLES: u16 = 2050;
+const VRAM_DMA_BLOCK_DOTS: u64 = 32; // 16 bytes take 8 µs at either speed
+
 pub struct Bus {
     cartridge: Cartridge,
     pub cpu: CPU,
@@ -17,42 +28,141 @@ pub struct Bus {
     pub io: IO,
     hram: HRam,
     pub ppu: PPU,
+    scheduler: Scheduler,
+    frame_ready: bool,
+    pub strict_mode: bool, // Report unmapped accesses as errors instead of emulating open bus
+    pub ppu_access_locking: bool, // Debug option, off lets the CPU access VRAM and OAM anytime
+    error: Option<EmulationError>,
+    instruction_pc: u16,
+    vram_dma_stall_m_cycles: u64, // M-cycles the CPU still has to wait for VRAM DMA
+    is_gbs_player: bool, // VBlank interrupts keep coming with the LCD off to call the play routine
 }
 
 impl Bus {
     pub fn new(filename: &str) -> Bus {
-        Bus {
-            cartridge: Cartridge::new(filename),
+        Bus::with_cartridge(Cartridge::new(filename))
+    }
+
+    // Plays a song from a GBS file through a cartridge built around its sound driver. Nothing is
+    // drawn, the player code keeps the LCD off
+    pub fn new_gbs(gbs: &GbsFile, song: u8) -> Bus {
+        let mut bus = Bus::with_cartridge(Cartridge::from_data(gbs.build_rom(song)));
+        bus.is_gbs_player = true;
+        bus
+    }
+
+    fn with_cartridge(cartridge: Cartridge) -> Bus {
+        let mut bus = Bus {
+            cartridge,
             cpu: CPU::default(),
             wram: WRam::default(),
             hram: HRam::default(),
             io: IO::default(),
             ppu: PPU::new(),
+            scheduler: Scheduler::default(),
+            frame_ready: false,
+            strict_mode: false,
+            ppu_access_locking: true,
+            error: None,
+            instruction_pc: 0,
+            vram_dma_stall_m_cycles: 0,
+            is_gbs_player: false,
+        };
+
+        if bus.cartridge.is_cgb() {
+            bus.io.set_cgb_mode(true);
+            bus.wram.cgb_mode = true;
+
+            // The CGB boot ROM hands over with A = 0x11, games check it to detect a CGB
+            bus.cpu.set_af(0x1180);
+            bus.cpu.set_bc(0x0000);
+            bus.cpu.set_de(0xFF56);
+            bus.cpu.set_hl(0x000D);
         }
+
+        // The first frame starts right away, boot hands over at the end of the last scanline
+        bus.scheduler.schedule(EventKind::PPUModeChange, 0);
+        bus.scheduler.schedule(
+            EventKind::APUFrameSequencer,
+            bus.io.timer.next_div_apu_time(),
+        );
+
+        bus
     }
 
     fn read_instruction(&mut self) -> u8 {
         let pc = self.cpu.get_pc_and_increment();
+        self.instruction_pc = pc;
 
         let opcode = self.read_u8(pc);
 
         opcode
     }
 
-    pub fn read_u16(&self, address: u16) -> u16 {
-        let memory_location = MemoryLocation::parse_address(address);
-        let u16_value = match memory_location {
-            MemoryLocation::Bank0 | MemoryLocation::BankN => self.cartridge.read_u16(address),
-            MemoryLocation::WorkRamBank0
-            | MemoryLocation::WorkRamBankN
-            | MemoryLocation::EchoRam => self.wram.read_u16(address),
-            _ => panic!("[Read u16] Unimplemented Memory Location 0x{:04X}", address),
-        };
+    // CPU memory accesses, each takes one M-cycle
+
+    pub fn read_u16(&mut self, address: u16) -> u16 {
+        let low_byte = self.read_u8(address) as u16;
+        let high_byte = self.read_u8(address.wrapping_add(1)) as u16;
+
+        (high_byte << 8) | low_byte
+    }
+
+    pub fn read_u8(&mut self, address: u16) -> u8 {
+        self.tick_m_cycle();
+        self.sync_io(address);
+
+        if self.strict_mode && !self.is_mapped(address) {
+            self.report_error(EmulationError::UnmappedRead {
+                pc: self.instruction_pc,
+                address,
+            });
+        }
+
+        if self.is_dma_conflict(address) {
+            return self.read_dma_conflict_u8(address);
+        }
+
+        if self.is_ppu_locked(address) {
+            return 0xFF;
+        }
+
+        self.read_memory_u8(address)
+    }
 
-        u16_value
+    pub fn write_u8(&mut self, address: u16, value: u8) {
+        self.tick_m_cycle();
+        self.sync_io(address);
+
+        if self.strict_mode && !self.is_mapped(address) {
+            self.report_error(EmulationError::UnmappedWrite {
+                pc: self.instruction_pc,
+                address,
+                value,
+            });
+        }
+
+        // Writes that conflict with OAM DMA or the PPU are lost
+        if self.is_dma_conflict(address) || self.is_ppu_locked(address) {
+            return;
+        }
+
+        self.write_memory_u8(address, value);
+
+        self.schedule_io_events(address);
+    }
+
+    pub fn write_u16(&mut self, address: u16, value: u16) {
+        let low_byte = (value & 0xFF) as u8;
+        let high_byte = (value >> 8) as u8;
+
+        self.write_u8(address, low_byte);
+        self.write_u8(address + 1, high_byte);
     }
 
-    pub fn read_u8(&self, address: u16) -> u8 {
+    // Memory accesses that don't advance the clock, used by DMA
+
+    fn read_memory_u8(&self, address: u16) -> u8 {
         let memory_location = MemoryLocation::parse_address(address);
         let u8_value = match memory_location {
             MemoryLocation::Bank0 | MemoryLocation::BankN | MemoryLocation::ExternalRam => {
@@ -61,18 +171,20 @@ impl Bus {
             MemoryLocation::WorkRamBank0
             | MemoryLocation::WorkRamBankN
             | MemoryLocation::EchoRam => self.wram.read_u8(address),
+            // SVBK belongs to WRAM rather than the rest of the IO registers
+            MemoryLocation::IO if address == 0xFF70 => self.wram.read_u8(address),
             MemoryLocation::HRam => self.hram.read_u8(address),
             MemoryLocation::IO
             | MemoryLocation::InterruptEnableRegister
             | MemoryLocation::VRam
             | MemoryLocation::Oam => self.io.read_u8(address),
-            _ => panic!("[Read u8] Unimplemented Memory Location 0x{:04X}", address),
+            MemoryLocation::NotUsed => 0x00, // DMG returns 0 while OAM is accessible
         };
 
         u8_value
     }
 
-    pub fn write_u8(&mut self, address: u16, value: u8) {
+    fn write_memory_u8(&mut self, address: u16, value: u8) {
         let memory_location = MemoryLocation::parse_address(address);
         match memory_location {
             MemoryLocation::Bank0 | MemoryLocation::BankN | MemoryLocation::ExternalRam => {
@@ -81,32 +193,170 @@ impl Bus {
             MemoryLocation::WorkRamBank0
             | MemoryLocation::WorkRamBankN
             | MemoryLocation::EchoRam => self.wram.write_u8(address, value),
+            MemoryLocation::IO if address == 0xFF70 => self.wram.write_u8(address, value),
             MemoryLocation::IO
             | MemoryLocation::InterruptEnableRegister
             | MemoryLocation::VRam
             | MemoryLocation::Oam => self.io.write_u8(address, value),
             MemoryLocation::HRam => self.hram.write_u8(address, value),
             MemoryLocation::NotUsed => {} //No-op
-            _ => panic!(
-                "[Write u8] Unimplemented Memory Location 0x{:04X} for value {:02x}",
-                address, value
-            ),
         };
     }
 
-    pub fn write_u16(&mut self, address: u16, value: u16) {
-        let low_byte = (value & 0xFF) as u8;
-        let high_byte = (value >> 8) as u8;
+    fn is_mapped(&self, address: u16) -> bool {
+        match MemoryLocation::parse_address(address) {
+            MemoryLocation::NotUsed => false,
+            MemoryLocation::IO => address == 0xFF70 || self.io.is_mapped(address),
+            _ => true,
+        }
+    }
 
-        self.write_u8(address, low_byte);
-        self.write_u8(address + 1, high_byte);
+    // While OAM DMA is running the CPU can't reach OAM or the bus the transfer is reading from
+    fn is_dma_conflict(&self, address: u16) -> bool {
+        if !self.io.oam.dma_transfer {
+            return false;
+        }
+
+        let memory_location = MemoryLocation::parse_address(address);
+        match memory_location {
+            MemoryLocation::Oam | MemoryLocation::NotUsed => true,
+            _ => {
+                let dma_location = MemoryLocation::parse_address(self.io.oam.dma_source);
+                let memory_bus = memory_location.get_memory_bus();
+
+                memory_bus != MemoryBus::Internal && memory_bus == dma_location.get_memory_bus()
+            }
+        }
     }
 
-    pub fn run_cycle(&mut self) -> bool {
-        let temp = self.io.interrupt.check_interrupts();
-        if self.io.interrupt.check_interrupts() > 0 {
-            self.cpu.add_m_cycles(5);
+    fn read_dma_conflict_u8(&self, address: u16) -> u8 {
+        match MemoryLocation::parse_address(address) {
+            MemoryLocation::Oam | MemoryLocation::NotUsed => 0xFF,
+            _ => self.io.oam.dma_value, // The CPU reads whatever the DMA put on the bus
+        }
+    }
+
+    fn is_ppu_locked(&self, address: u16) -> bool {
+        if !self.ppu_access_locking {
+            return false;
+        }
+
+        match MemoryLocation::parse_address(address) {
+            MemoryLocation::VRam => !self.io.lcd.is_vram_accessible(),
+            MemoryLocation::Oam => !self.io.lcd.is_oam_accessible(),
+            _ => false,
+        }
+    }
+
+    // Only the first error is kept, the instruction still completes before it is reported
+    fn report_error(&mut self, error: EmulationError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    // Timing
+
+    // Runs a DMG game the way a CGB does, the boot ROM colors it with palettes picked by its title
+    // unless a button combination picked one of the manual palettes
+    pub fn enable_dmg_compatibility(&mut self, manual_palette: Option<ManualPalette>) {
+        if self.io.cgb_mode {
+            return;
+        }
+
+        let palettes = match manual_palette {
+            Some(manual_palette) => manual_palette.get_palettes(),
+            None => get_title_palettes(self.cartridge.get_title_checksum()),
+        };
+        self.io.lcd.load_compatibility_palettes(palettes);
+
+        // DMG games are also handed A = 0x11 on a CGB
+        self.cpu.set_af(0x1180);
+        self.cpu.set_bc(0x0000);
+        self.cpu.set_de(0x0008);
+        self.cpu.set_hl(0x007C);
+    }
+
+    // Runs a DMG game on a Super Game Boy, which colors the screen and draws a border around it.
+    // Games without SGB support in their header still get the border and default palette
+    pub fn enable_sgb(&mut self) {
+        if self.io.cgb_mode {
+            return;
+        }
+
+        self.io.sgb.enable(self.cartridge.is_sgb());
+
+        // The SGB boot ROM hands over with A = 0x01 like a DMG
+        self.cpu.set_af(0x0100);
+        self.cpu.set_bc(0x0014);
+        self.cpu.set_de(0x0000);
+        self.cpu.set_hl(0xC060);
+    }
+
+    pub fn cycles_elapsed(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    // Stereo samples produced since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<StereoSample> {
+        self.io.sound.sync(self.cpu.cycles);
+        self.io.sound.take_samples()
+    }
+
+    // Each channel's samples on their own since the last call, in step with take_audio_samples
+    pub fn take_audio_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        self.io.sound.sync(self.cpu.cycles);
+        self.io.sound.take_channel_samples()
+    }
+
+    // Muted channels are left out of the mix, but still have their own samples
+    pub fn set_muted_audio_channels(&mut self, muted_channels: [bool; 4]) {
+        self.io.sound.sync(self.cpu.cycles);
+        self.io.sound.set_muted_channels(muted_channels);
+    }
+
+    pub fn read_sound_registers(&mut self) -> [u8; 0x17] {
+        self.io.sound.sync(self.cpu.cycles);
+        self.io.sound.get_registers()
+    }
+
+    // Runs for at least the given number of T-cycles, returns true if a frame was completed
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<bool, EmulationError> {
+        let target_cycle = self.cycles_elapsed() + cycles;
+        self.run_until_cycle(target_cycle)
+    }
+
+    // Runs until the T-cycle counter reaches the target, returns true if a frame was completed
+    // Instructions are not split, so the counter may overshoot the target by a few cycles
+    pub fn run_until_cycle(&mut self, target_cycle: u64) -> Result<bool, EmulationError> {
+        let mut frame_completed = false;
+
+        while self.cycles_elapsed() < target_cycle {
+            frame_completed |= self.run_cycle()?;
+        }
+
+        Ok(frame_completed)
+    }
+
+    fn tick_m_cycle(&mut self) {
+        self.cpu.add_m_cycles(1);
+
+        if self.scheduler.next_event_time() <= self.cpu.cycles {
+            self.run_due_events();
+        }
+    }
+
+    // Spends whatever M-cycles of an instruction were not already used by memory accesses
+    pub fn complete_m_cycles(&mut self, cycles: u16) {
+        while (self.cpu.cycle_buffer as u16) < cycles {
+            self.tick_m_cycle();
+        }
+
+        self.cpu.cycle_buffer = 0;
+    }
 
+    pub fn run_cycle(&mut self) -> Result<bool, EmulationError> {
+        if self.io.interrupt.check_interrupts() > 0 {
             self.cpu.resume();
             self.io.interrupt.disable_interrupts();
             self.push_u16_to_stack(self.cpu.get_pc());
@@ -121,9 +371,18 @@ impl Bus {
             } else if self.io.interrupt.is_timer_interrupt() {
                 self.cpu.set_pc(0x0050);
                 self.io.interrupt.disable_timer_interrupt();
+            } else if self.io.interrupt.is_serial_interrupt() {
+                self.cpu.set_pc(0x0058);
+                self.io.interrupt.disable_serial_interrupt();
+            } else if self.io.interrupt.is_joypad_interrupt() {
+                self.cpu.set_pc(0x0060);
+                self.io.interrupt.disable_joypad_interrupt();
             } else {
-                panic!("Unimplemented Interrupt {:08b}", temp);
+                // Pushing the PC over IE can cancel the interrupt, the CPU then jumps to 0x0000
+                self.cpu.set_pc(0x0000);
             }
+
+            self.complete_m_cycles(5);
         } else {
             if self.cpu.is_halted {
                 // Handle case where master interrupt is disabled but individual interrupts are enabled
@@ -132,52 +391,283 @@ impl Bus {
                     self.cpu.resume();
                 }
 
-                self.cpu.add_m_cycles(1);
+                self.complete_m_cycles(1);
             } else {
                 let opcode = self.read_instruction();
                 execute_opcode(self, opcode);
             }
         }
 
-        // TODO: Figure out cycle timing
-        if self.io.oam.dma_transfer {
-            let start_address = (self.io.oam.dma as u16) << 8;
-            for i in 0..0xA0 {
-                let value = self.read_u8(start_address + i);
-                self.io.oam.oam[i as usize] = value;
+        // The CPU waits while VRAM DMA copies blocks
+        while self.vram_dma_stall_m_cycles > 0 {
+            self.vram_dma_stall_m_cycles -= 1;
+            self.complete_m_cycles(1);
+        }
+
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        let should_render = self.frame_ready;
+        self.frame_ready = false;
+
+        Ok(should_render)
+    }
+
+    // Events
+
+    fn run_due_events(&mut self) {
+        while let Some((event_kind, time)) = self.scheduler.pop_due_event(self.cpu.cycles) {
+            match event_kind {
+                EventKind::PPUModeChange => self.handle_ppu_mode_change(time),
+                EventKind::TimerOverflow => {
+                    self.io.timer.handle_event(time, &mut self.io.interrupt);
+                    self.schedule_timer_overflow();
+                }
+                EventKind::SerialBit => {
+                    self.io.serial.shift_bit(&mut self.io.interrupt);
+                    if self.io.serial.is_transferring() {
+                        self.scheduler
+                            .schedule(EventKind::SerialBit, time + self.serial_bit_cycles());
+                    }
+                }
+                EventKind::OamDmaStep => self.handle_oam_dma_step(time),
+                EventKind::APUFrameSequencer => {
+                    self.io.sound.sync(time);
+                    self.io.sound.step_frame_sequencer();
+                    self.io.timer.sync(time);
+                    self.scheduler.schedule(
+                        EventKind::APUFrameSequencer,
+                        self.io.timer.next_div_apu_time(),
+                    );
+                }
             }
-            self.io.oam.dma_transfer = false;
         }
+    }
+
+    fn handle_ppu_mode_change(&mut self, time: u64) {
+        // Blank frames keep coming at the usual rate while the LCD is off
+        if !self.io.lcd.is_on() {
+            self.frame_ready = true;
+            if self.is_gbs_player {
+                self.io.interrupt.set_vblank_interrupt();
+            }
+            self.scheduler
+                .schedule(EventKind::PPUModeChange, time + FRAME_DOTS);
+            return;
+        }
+
+        // Mode 3 lasts until the FIFOs have drawn the whole scanline, which depends on scrolling,
+        // the window and objects, so check again once it could have finished
+        if self.io.lcd.get_mode() == 3 {
+            self.ppu.sync(&self.io, time);
 
-        let scanline_event: ScanLineEvent = self
+            if let Some(dots_remaining) = self.ppu.get_min_dots_remaining() {
+                self.scheduler
+                    .schedule(EventKind::PPUModeChange, time + dots_remaining);
+                return;
+            }
+        }
+
+        let (scanline_event, dots_until_next_mode) = self
             .io
             .lcd
-            .update_ly(self.cpu.cycle_buffer, &mut self.io.interrupt);
+            .update_mode(&mut self.io.interrupt, self.ppu.get_pixel_transfer_dots());
 
-        let mut should_render = false;
+        self.scheduler
+            .schedule(EventKind::PPUModeChange, time + dots_until_next_mode);
 
         match scanline_event {
             ScanLineEvent::OAMScanEntered => {
                 // Build line object buffer
                 self.ppu.update_scanline_object_id_buffer(&self.io);
+                self.ppu.update_window_y_trigger(&self.io);
+
+                if self.io.lcd.lcd_y_coordinate == 0 {
+                    self.ppu.is_lcd_off_frame = false;
+                }
+            }
+            ScanLineEvent::PixelTransferEntered => {
+                self.ppu.start_pixel_transfer(&self.io, time);
             }
             ScanLineEvent::HBlankEntered => {
-                // Build scanline
-                self.ppu.update_current_scanline_in_frame_buffer(&self.io);
+                // Draw whatever the FIFOs haven't caught up on yet
+                self.ppu.finish_scanline(&self.io);
+
+                if self.io.vram.is_hblank_dma_active {
+                    self.copy_vram_dma_block();
+                }
             }
             ScanLineEvent::VBlankEntered => {
                 // Render screen
-                should_render = true;
-                self.ppu.window_internal_line_counter = 0;
+                self.frame_ready = true;
+                self.ppu.reset_window();
+
+                if self.io.sgb.is_enabled {
+                    self.io.sgb.update_frame(&self.ppu.shade_buffer);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Copies one byte per M-cycle, OAM stays locked for the M-cycle of the last byte
+    fn handle_oam_dma_step(&mut self, time: u64) {
+        if let Some(start_time) = self.io.oam.dma_start_time {
+            if start_time <= time {
+                self.io.oam.start_dma_transfer();
+            }
+        }
+
+        if self.io.oam.dma_transfer {
+            let index = self.io.oam.dma_index;
+
+            if index < 0xA0 {
+                let value = self.read_memory_u8(self.io.oam.dma_source + index as u16);
+                self.io.oam.oam[index as usize] = value;
+                self.io.oam.dma_value = value;
+                self.io.oam.dma_index += 1;
+            } else {
+                self.io.oam.dma_transfer = false;
+            }
+        }
+
+        if self.io.oam.dma_transfer {
+            self.scheduler
+                .schedule(EventKind::OamDmaStep, time + self.cpu.m_cycle_duration());
+        } else if let Some(start_time) = self.io.oam.dma_start_time {
+            self.scheduler.schedule(EventKind::OamDmaStep, start_time);
+        }
+    }
+
+    // Copies the next 16 bytes of a VRAM DMA into the selected VRAM bank
+    fn copy_vram_dma_block(&mut self) {
+        let (source, destination) = self.io.vram.get_dma_block();
+
+        for offset in 0..16 {
+            let value = self.read_memory_u8(source.wrapping_add(offset));
+            self.io.vram.write_u8(0x8000 + destination + offset, value);
+        }
+
+        self.io.vram.advance_dma();
+        self.vram_dma_stall_m_cycles += VRAM_DMA_BLOCK_DOTS / self.cpu.m_cycle_duration();
+    }
+
+    // The serial clock is divided from the CPU clock, so it also doubles in double speed
+    fn serial_bit_cycles(&self) -> u64 {
+        SERIAL_BIT_CYCLES * self.cpu.m_cycle_duration() / 4
+    }
+
+    fn schedule_timer_overflow(&mut self) {
+        match self.io.timer.next_event_time() {
+            Some(time) => self.scheduler.schedule(EventKind::TimerOverflow, time),
+            None => self.scheduler.cancel(EventKind::TimerOverflow),
+        }
+    }
+
+    // IO registers are synced lazily, only when accessed
+    fn sync_io(&mut self, address: u16) {
+        match address {
+            0xFF04..=0xFF07 => self.io.timer.sync(self.cpu.cycles),
+            0xFF10..=0xFF3F => self.io.sound.sync(self.cpu.cycles),
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B | 0xFF55 | 0xFF68..=0xFF6B => {
+                self.ppu.sync(&self.io, self.cpu.cycles)
+            }
+            _ => {}
+        }
+    }
+
+    // Reschedules the events of an IO register's component after it was written
+    fn schedule_io_events(&mut self, address: u16) {
+        match address {
+            0xFF40 => self.update_lcd_power(),
+            0xFF04 => {
+                self.schedule_timer_overflow();
+                self.scheduler.schedule(
+                    EventKind::APUFrameSequencer,
+                    self.io.timer.next_div_apu_time(),
+                );
+            }
+            0xFF05..=0xFF07 => self.schedule_timer_overflow(),
+            0xFF02 => {
+                if self.io.serial.is_transferring() {
+                    self.scheduler.schedule(
+                        EventKind::SerialBit,
+                        self.cpu.cycles + self.serial_bit_cycles(),
+                    );
+                } else {
+                    self.scheduler.cancel(EventKind::SerialBit);
+                }
+            }
+            0xFF46 => {
+                // One M-cycle of setup, the first byte is copied in the M-cycle after it
+                let start_time = self.cpu.cycles + 2 * self.cpu.m_cycle_duration();
+                self.io.oam.dma_start_time = Some(start_time);
+
+                // A running transfer already has a step scheduled every M-cycle
+                if !self.io.oam.dma_transfer {
+                    self.scheduler.schedule(EventKind::OamDmaStep, start_time);
+                }
+            }
+            0xFF55 => {
+                // General purpose DMA copies everything right away, an HBlank DMA started while
+                // the PPU is in HBlank or off copies its first block right away
+                if self.io.vram.is_general_dma_requested {
+                    while self.io.vram.is_general_dma_requested {
+                        self.copy_vram_dma_block();
+                    }
+                } else if self.io.vram.is_hblank_dma_active
+                    && (!self.io.lcd.is_on() || self.io.lcd.get_mode() == 0)
+                {
+                    self.copy_vram_dma_block();
+                }
             }
             _ => {}
         }
+    }
+
+    // STOP resets DIV and switches the CPU speed if KEY1 requested it. Otherwise the CPU would
+    // wait for a joypad press in low power mode, which is treated like HALT
+    pub fn stop(&mut self) {
+        self.io.timer.sync(self.cpu.cycles);
+        self.io.timer.write_u8(0xFF04, 0);
+
+        if self.io.cgb_mode && self.io.cgb_registers.is_speed_switch_requested() {
+            self.io.cgb_registers.switch_speed();
+
+            let is_double_speed = self.io.cgb_registers.is_double_speed();
+            self.cpu.is_double_speed = is_double_speed;
+            self.io.timer.is_double_speed = is_double_speed;
+            self.schedule_io_events(0xFF04);
+
+            // The CPU is stopped while the clock switches
+            for _ in 0..SPEED_SWITCH_M_CYCLES {
+                self.complete_m_cycles(1);
+            }
+        } else {
+            self.schedule_io_events(0xFF04);
+            self.cpu.halt();
+        }
+    }
+
+    // LCDC bit 7 stops or restarts the PPU
+    fn update_lcd_power(&mut self) {
+        let time = self.cpu.cycles;
 
-        self.io
-            .timer
-            .update_timer(&mut self.cpu.cycle_buffer, &mut self.io.interrupt);
+        if self.io.lcd.is_lcd_enabled() && !self.io.lcd.is_on() {
+            let dots_until_next_mode = self.io.lcd.turn_on(&mut self.io.interrupt);
 
-        should_render
+            // OAM is still scanned on the first line, STAT just doesn't report it
+            self.ppu.update_scanline_object_id_buffer(&self.io);
+            self.ppu.update_window_y_trigger(&self.io);
+            self.scheduler
+                .schedule(EventKind::PPUModeChange, time + dots_until_next_mode);
+        } else if !self.io.lcd.is_lcd_enabled() && self.io.lcd.is_on() {
+            self.io.lcd.turn_off(&mut self.io.interrupt);
+            self.ppu.turn_off();
+            self.scheduler
+                .schedule(EventKind::PPUModeChange, time + FRAME_DOTS);
+        }
     }
 
     pub fn push_u16_to_stack(&mut self, value: u16) {
@@ -197,3 +687,76 @@ impl Bus {
         ((high_byte as u16) << 8) | low_byte as u16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Bus;
+    use crate::cartridge::cartridge::Cartridge;
+
+    // A CGB cartridge that writes to KEY1, runs STOP and then loops forever
+    fn run_stop_program(key_1: u8) -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80; // CGB
+        rom[0x0100..0x0108].copy_from_slice(&[
+            0x3E, key_1, // LD A, key_1
+            0xE0, 0x4D, // LDH (KEY1), A
+            0x10, 0x00, // STOP
+            0x18, 0xFE, // JR back to itself
+        ]);
+
+        let mut bus = Bus::with_cartridge(Cartridge::from_data(rom));
+        while bus.cpu.get_pc() != 0x0106 {
+            bus.run_cycle().unwrap();
+        }
+
+        bus
+    }
+
+    // DIV increments over the next 16384 T-cycles, 64 of them at normal speed
+    fn count_div_increments(bus: &mut Bus) -> u64 {
+        let start_time = bus.cycles_elapsed();
+        let mut increments = 0;
+        let mut div = read_div(bus);
+
+        while bus.cycles_elapsed() < start_time + 16384 {
+            bus.complete_m_cycles(1);
+
+            let next_div = read_div(bus);
+            increments += next_div.wrapping_sub(div) as u64;
+            div = next_div;
+        }
+
+        increments
+    }
+
+    fn read_div(bus: &mut Bus) -> u8 {
+        bus.io.timer.sync(bus.cycles_elapsed());
+        bus.io.timer.read_u8(0xFF04)
+    }
+
+    #[test]
+    fn stop_switches_to_double_speed_when_requested() {
+        let mut bus = run_stop_program(0x01);
+
+        assert_eq!(bus.io.read_u8(0xFF4D), 0xFE);
+        assert_eq!(bus.cpu.m_cycle_duration(), 2);
+        assert!(!bus.cpu.is_halted);
+
+        let (start_time, start_m_cycles) = (bus.cycles_elapsed(), bus.cpu.m_cycles);
+        bus.complete_m_cycles(1);
+        assert_eq!(bus.cycles_elapsed() - start_time, 2);
+        assert_eq!(bus.cpu.m_cycles - start_m_cycles, 1);
+
+        assert_eq!(count_div_increments(&mut bus), 128);
+    }
+
+    #[test]
+    fn stop_keeps_normal_speed_without_a_request() {
+        let mut bus = run_stop_program(0x00);
+
+        assert_eq!(bus.io.read_u8(0xFF4D), 0x7E);
+        assert_eq!(bus.cpu.m_cycle_duration(), 4);
+        assert!(bus.cpu.is_halted);
+        assert_eq!(count_div_increments(&mut bus), 64);
+    }
+}
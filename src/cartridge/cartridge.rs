@@ -15,7 +15,10 @@ pub struct Cartridge {
 
 impl Cartridge {
     pub fn new(filename: &str) -> Cartridge {
-        let file_data = read_file(filename).unwrap();
+        Cartridge::from_data(read_file(filename).unwrap())
+    }
+
+    pub fn from_data(file_data: Vec<u8>) -> Cartridge {
         let header = CartridgeHeader::new(file_data.clone());
         let chip_type = CartridgeChipType::from(header.cartridge_type);
 
@@ -75,6 +78,7 @@ impl Cartridge {
     pub fn read_u8_rom(&self, address: u16) -> u8 {
         match address {
             0x0000..=0x7FFF => self.file_data[address as usize],
+            0xA000..=0xBFFF => 0xFF, // No external RAM, open bus
             _ => panic!(
                 "[ROM ONLY] Invalid Cartridge Read address: 0x{:04X}",
                 address
@@ -82,6 +86,18 @@ impl Cartridge {
         }
     }
 
+    pub fn is_cgb(&self) -> bool {
+        self.header.is_cgb()
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        self.header.is_sgb()
+    }
+
+    pub fn get_title_checksum(&self) -> Option<(u8, u8)> {
+        self.header.get_title_checksum()
+    }
+
     pub fn read_u8(&self, address: u16) -> u8 {
         match self.chip_type {
             CartridgeChipType::MBC1 => self.read_u8_mbc1(address),
@@ -91,18 +107,6 @@ impl Cartridge {
         }
     }
 
-    pub fn read_u16(&self, address: u16) -> u16 {
-        match address {
-            0x0000..=0x7FFF => {
-                let low_byte = self.read_u8(address) as u16;
-                let high_byte = self.read_u8(address + 1) as u16;
-
-                (high_byte << 8) | low_byte
-            }
-            _ => panic!("Invalid Cartridge Read address: 0x{:04X}", address),
-        }
-    }
-
     fn write_u8_mbc1(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
@@ -110,7 +114,7 @@ impl Cartridge {
                 self.rom_bank = value & self.header.get_rom_bank_mask();
             }
             0x4000..=0x5FFF => self.ram_bank = value & 0x03,
-            0x6000..=0x7FFF => panic!("MBC1: Unimplemented Banking Mod Selectt: 0x{:02X}", value),
+            0x6000..=0x7FFF => (), // TODO: Implement MBC1 banking mode select
             0xA000..=0xBFFF => {
                 if self.ram_enabled {
                     let bank = self.ram_bank as usize;
@@ -151,7 +155,7 @@ impl Cartridge {
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match self.chip_type {
             CartridgeChipType::MBC1 => self.write_u8_mbc1(address, value),
-            CartridgeChipType::ROMOnly => panic!("ROM Only Cartridge cannot be written to"),
+            CartridgeChipType::ROMOnly => (), // Writes to ROM without a mapper are ignored
             CartridgeChipType::MBC3 => self.write_u8_mbc3(address, value),
             _ => panic!("Cartridge Chip Type not implemented: {:?}", self.chip_type),
         }
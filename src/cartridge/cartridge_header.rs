@@ -149,22 +149,27 @@ pub struct CartridgeHeader {
     entry_point: u32,
     title: Vec<u8>,
     cgb_flag: u8,
+    new_licensee_code: [u8; 2],
     sgb_flag: u8,
     pub cartridge_type: CartridgeType,
     rom_size: RomSize,
     ram_size: RamSize,
+    old_licensee_code: u8,
 }
 
 impl CartridgeHeader {
     pub fn new(file_data: Vec<u8>) -> CartridgeHeader {
         let entry_point: u32 = BigEndian::read_u32(&file_data[0x100..0x104]);
-        let title: Vec<u8> = file_data[0x134..0x143].to_vec();
+        // DMG titles can use all 16 bytes, the last one is the CGB flag on later games
+        let title: Vec<u8> = file_data[0x134..0x144].to_vec();
         let cgb_flag: u8 = file_data[0x143];
+        let new_licensee_code: [u8; 2] = [file_data[0x144], file_data[0x145]];
         let sgb_flag: u8 = file_data[0x146];
 
         let cartridge_type: CartridgeType = CartridgeType::from(file_data[0x147]);
         let rom_size: RomSize = RomSize::from(file_data[0x148]);
         let ram_size: RamSize = RamSize::from(file_data[0x149]);
+        let old_licensee_code: u8 = file_data[0x14B];
 
         println!("Rom Size: {:?}", rom_size);
         println!("Ram Size: {:?}", ram_size);
@@ -173,14 +178,50 @@ impl CartridgeHeader {
             entry_point,
             title,
             cgb_flag,
+            new_licensee_code,
             sgb_flag,
             cartridge_type,
             rom_size,
             ram_size,
+            old_licensee_code,
         }
     }
 
     pub fn get_rom_bank_mask(&self) -> u8 {
         self.rom_size.get_rom_bank_mask()
     }
+
+    // 0x80 is a CGB enhanced game, 0xC0 is CGB only
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    // SGB functions need the SGB flag and the new licensee code
+    pub fn is_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    // 0x33 means the licensee is in the new 2 letter code
+    fn is_nintendo_licensee(&self) -> bool {
+        match self.old_licensee_code {
+            0x01 => true,
+            0x33 => &self.new_licensee_code == b"01",
+            _ => false,
+        }
+    }
+
+    // The sum of the title bytes and the 4th letter, the CGB boot ROM picks the palettes of
+    // Nintendo's DMG games with them
+    pub fn get_title_checksum(&self) -> Option<(u8, u8)> {
+        if !self.is_nintendo_licensee() {
+            return None;
+        }
+
+        let checksum = self
+            .title
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+        Some((checksum, self.title[3]))
+    }
 }
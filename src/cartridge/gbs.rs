@@ -0,0 +1,206 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::cartridge::read_file;
+
+const HEADER_SIZE: usize = 0x70;
+const BANK_SIZE: usize = 0x4000;
+const MAX_ROM_SIZE_CODE: u8 = 0x06; // 2MB, the largest the MBC1 banking supports
+const PLAYER_ADDRESS: u16 = 0x0150;
+
+// GBS files hold a game's sound driver and music data, with the addresses to call to start a song
+// and to play each tick of it
+pub struct GbsFile {
+    pub song_count: u8,
+    pub first_song: u8, // Songs are numbered from 1 in the header, 0 when passed to init
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    stack_pointer: u16,
+    timer_modulo: u8,
+    timer_control: u8, // Bit 2 plays from the timer instead of VBlank, bit 7 is double speed
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    InvalidHeader,
+    InvalidLoadAddress(u16),
+    TooLarge,
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::Io(error) => write!(f, "{}", error),
+            GbsError::InvalidHeader => write!(f, "Not a version 1 GBS file"),
+            GbsError::InvalidLoadAddress(address) => {
+                write!(f, "Load address 0x{:04X} is outside 0x0400-0x7FFF", address)
+            }
+            GbsError::TooLarge => write!(f, "GBS data doesn't fit in a 2MB cartridge"),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+impl GbsFile {
+    // Only the identifier and version are read, the version is checked when loading
+    pub fn is_gbs_file(filename: &str) -> bool {
+        let mut header = [0; 4];
+
+        File::open(filename)
+            .and_then(|mut file| file.read_exact(&mut header))
+            .is_ok_and(|_| header.starts_with(b"GBS"))
+    }
+
+    pub fn load(filename: &str) -> Result<GbsFile, GbsError> {
+        let file_data = read_file(filename).map_err(GbsError::Io)?;
+
+        if file_data.len() < HEADER_SIZE || !file_data.starts_with(b"GBS") || file_data[3] != 1 {
+            return Err(GbsError::InvalidHeader);
+        }
+
+        let load_address = LittleEndian::read_u16(&file_data[0x06..0x08]);
+        if !(0x0400..=0x7FFF).contains(&load_address) {
+            return Err(GbsError::InvalidLoadAddress(load_address));
+        }
+
+        let gbs = GbsFile {
+            song_count: file_data[0x04],
+            first_song: file_data[0x05].max(1),
+            load_address,
+            init_address: LittleEndian::read_u16(&file_data[0x08..0x0A]),
+            play_address: LittleEndian::read_u16(&file_data[0x0A..0x0C]),
+            stack_pointer: LittleEndian::read_u16(&file_data[0x0C..0x0E]),
+            timer_modulo: file_data[0x0E],
+            timer_control: file_data[0x0F],
+            title: read_text(&file_data[0x10..0x30]),
+            author: read_text(&file_data[0x30..0x50]),
+            copyright: read_text(&file_data[0x50..0x70]),
+            data: file_data[HEADER_SIZE..].to_vec(),
+        };
+
+        if gbs.get_rom_size_code().is_none() {
+            return Err(GbsError::TooLarge);
+        }
+
+        Ok(gbs)
+    }
+
+    pub fn is_timer_driven(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    fn is_double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    // The header's ROM size, the cartridge is at least 32KB and doubles from there
+    fn get_rom_size_code(&self) -> Option<u8> {
+        let size = self.load_address as usize + self.data.len();
+
+        (0..=MAX_ROM_SIZE_CODE).find(|code| size <= (2 * BANK_SIZE) << code)
+    }
+
+    // Builds an MBC1 cartridge with the GBS data at its load address, banked the same way. The
+    // code in front of it calls init for the song, then halts and leaves the play calls to the
+    // VBlank or timer interrupt. RST vectors are redirected to the load address
+    pub fn build_rom(&self, song: u8) -> Vec<u8> {
+        let rom_size_code = self.get_rom_size_code().unwrap_or(MAX_ROM_SIZE_CODE);
+        let mut rom = vec![0; (2 * BANK_SIZE) << rom_size_code];
+
+        let load_address = self.load_address as usize;
+        rom[load_address..load_address + self.data.len()].copy_from_slice(&self.data);
+
+        for vector in (0x00..0x40).step_by(8) {
+            write_code(&mut rom, vector, &jump(0xC3, self.load_address + vector));
+        }
+        // CALL play, RETI
+        let [play_low, play_high] = self.play_address.to_le_bytes();
+        write_code(&mut rom, 0x40, &[0xCD, play_low, play_high, 0xD9]);
+        write_code(&mut rom, 0x50, &[0xCD, play_low, play_high, 0xD9]);
+
+        // NOP, JP to the player
+        write_code(&mut rom, 0x0100, &[0x00]);
+        write_code(&mut rom, 0x0101, &jump(0xC3, PLAYER_ADDRESS));
+        rom[0x0143] = if self.is_double_speed() { 0x80 } else { 0x00 };
+        rom[0x0147] = 0x02; // MBC1 with RAM
+        rom[0x0148] = rom_size_code;
+        rom[0x0149] = 0x02; // 8KB
+
+        write_code(&mut rom, PLAYER_ADDRESS, &self.get_player_code(song));
+
+        rom
+    }
+
+    #[rustfmt::skip]
+    fn get_player_code(&self, song: u8) -> Vec<u8> {
+        let [stack_low, stack_high] = self.stack_pointer.to_le_bytes();
+        let [init_low, init_high] = self.init_address.to_le_bytes();
+        let interrupt_enable = if self.is_timer_driven() { 0x04 } else { 0x01 };
+
+        let mut code = vec![
+            0xF3, // DI
+            0x31, stack_low, stack_high, // LD SP, stack pointer
+            0xAF, // XOR A
+            0xE0, 0x40, // LDH (LCDC), A, the LCD stays off
+            0x3E, 0x0A, // LD A, 0x0A
+            0xEA, 0x00, 0x00, // LD (0x0000), A, enables cartridge RAM
+        ];
+
+        if self.is_double_speed() {
+            code.extend_from_slice(&[
+                0x3E, 0x01, // LD A, 0x01
+                0xE0, 0x4D, // LDH (KEY1), A
+                0x10, 0x00, // STOP
+            ]);
+        }
+
+        code.extend_from_slice(&[
+            0x3E, 0x80, 0xE0, 0x26, // NR52 = 0x80
+            0x3E, 0x77, 0xE0, 0x24, // NR50 = 0x77
+            0x3E, 0xFF, 0xE0, 0x25, // NR51 = 0xFF
+            0x3E, self.timer_modulo, 0xE0, 0x06, // TMA
+            0xE0, 0x05, // TIMA, so the first play call comes a full period later
+            0x3E, self.timer_control & 0x07, 0xE0, 0x07, // TAC
+            0x3E, song, // LD A, song
+            0xCD, init_low, init_high, // CALL init
+            0x3E, interrupt_enable, 0xE0, 0xFF, // IE
+            0xAF, 0xE0, 0x0F, // IF = 0
+            0xFB, // EI
+            0x76, // HALT
+            0x18, 0xFD, // JR back to HALT
+        ]);
+
+        code
+    }
+}
+
+fn jump(opcode: u8, address: u16) -> [u8; 3] {
+    let [low, high] = address.to_le_bytes();
+    [opcode, low, high]
+}
+
+fn write_code(rom: &mut [u8], address: u16, code: &[u8]) {
+    let address = address as usize;
+    rom[address..address + code.len()].copy_from_slice(code);
+}
+
+// Text fields are padded with zeros
+fn read_text(bytes: &[u8]) -> String {
+    let length = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..length]).trim().to_string()
+}
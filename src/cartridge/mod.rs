@@ -1,2 +1,3 @@
 pub mod cartridge;
-mod cartridge_header;
\ No newline at end of file
+mod cartridge_header;
+pub mod gbs;
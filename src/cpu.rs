@@ -22,9 +22,11 @@ impl Registers {
 
 pub struct CPU {
     registers: Registers,
-    pub cycles: u16, // TODO: Figure out how to handle cycles
-    pub cycle_buffer: u8,
+    pub cycles: u64,      // Total T-cycles since power on
+    pub m_cycles: u64,    // Total M-cycles since power on
+    pub cycle_buffer: u8, // M-cycles already spent on the current instruction
     pub is_halted: bool,
+    pub is_double_speed: bool, // CGB double speed, M-cycles take 2 T-cycles instead of 4
 }
 
 impl CPU {
@@ -33,7 +35,9 @@ impl CPU {
             registers: Registers::default(),
             cycle_buffer: 0,
             cycles: 0,
+            m_cycles: 0,
             is_halted: false,
+            is_double_speed: false,
         }
     }
 
@@ -229,8 +233,18 @@ impl CPU {
     // -------------------------
 
     pub fn add_m_cycles(&mut self, cycles: u16) {
-        self.cycle_buffer = cycles as u8;
-        // self.cycles = self.cycles.wrapping_add(cycles);
+        self.cycle_buffer += cycles as u8;
+        self.m_cycles += cycles as u64;
+        self.cycles += cycles as u64 * self.m_cycle_duration();
+    }
+
+    // T-cycles per M-cycle, T-cycles always count at the normal speed clock rate
+    pub fn m_cycle_duration(&self) -> u64 {
+        if self.is_double_speed {
+            2
+        } else {
+            4
+        }
     }
 
     pub fn halt(&mut self) {
@@ -0,0 +1,27 @@
+use std::fmt;
+
+// Errors reported in strict mode, by default the emulator behaves like hardware instead
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EmulationError {
+    UnmappedRead { pc: u16, address: u16 },
+    UnmappedWrite { pc: u16, address: u16, value: u8 },
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::UnmappedRead { pc, address } => write!(
+                f,
+                "[0x{:04X}] Read from unmapped address 0x{:04X}",
+                pc, address
+            ),
+            EmulationError::UnmappedWrite { pc, address, value } => write!(
+                f,
+                "[0x{:04X}] Write of 0x{:02X} to unmapped address 0x{:04X}",
+                pc, value, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use crate::{
+    bus::Bus,
+    cartridge::gbs::GbsFile,
+    io::lcd::FRAME_DOTS,
+    recording::{finish_recording, record_frame, start_recording, AudioRecorder},
+};
+
+// Runs for a number of frames without a window as fast as possible, e.g. to record audio. Frames
+// are counted in cycles so they keep coming at the same rate while the LCD is off
+pub fn run_headless(bus: &mut Bus, frames: u32, mut recorder: Option<AudioRecorder>) {
+    for _ in 0..frames {
+        if let Err(error) = bus.run_for_cycles(FRAME_DOTS) {
+            println!("[Error] {}", error);
+            break;
+        }
+
+        let samples = bus.take_audio_samples();
+        let channel_samples = bus.take_audio_channel_samples();
+        record_frame(&mut recorder, &samples, &channel_samples);
+    }
+
+    if let Some(recorder) = recorder {
+        finish_recording(recorder);
+    }
+}
+
+// Records each song of a GBS file, or just the given one, to a file named after the GBS file and
+// the track number, e.g. music-03.wav
+pub fn gbs2wav(
+    gbs: &GbsFile,
+    filename: &str,
+    track: Option<u8>,
+    frames: u32,
+    record_channels: bool,
+    high_pass_filter: bool,
+) {
+    let gbs_name = Path::new(filename)
+        .file_stem()
+        .map_or(String::from("gbs"), |stem| {
+            stem.to_string_lossy().into_owned()
+        });
+    let tracks = match track {
+        Some(track) => track..=track,
+        None => 1..=gbs.song_count,
+    };
+
+    for track in tracks {
+        let path = format!("{}-{:02}.wav", gbs_name, track);
+
+        if let Some(recorder) = start_recording(&path, record_channels) {
+            let mut bus = Bus::new_gbs(gbs, track - 1);
+            bus.io.sound.mixer.is_high_pass_filter_enabled = high_pass_filter;
+            run_headless(&mut bus, frames, Some(recorder));
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::f64::consts::PI;
+
+// Band-limited synthesis. Channel outputs only ever change in steps, so instead of point sampling
+// them (which aliases badly at high frequencies) each change adds a band-limited step at its
+// exact time, spread over a few samples around it. Samples are the running sum of those deltas
+
+const KERNEL_WIDTH: usize = 16;
+const KERNEL_PHASES: usize = 64;
+const CUTOFF: f64 = 0.9; // Fraction of the Nyquist frequency that is kept
+
+pub struct BlipBuffer {
+    clocks_per_sample: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    deltas: Vec<f32>,
+    samples_read: u64, // Samples before the first delta in the buffer
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u64, sample_rate: u64) -> BlipBuffer {
+        BlipBuffer {
+            clocks_per_sample: clock_rate as f64 / sample_rate as f64,
+            kernel: (0..KERNEL_PHASES).map(get_kernel_phase).collect(),
+            deltas: Vec::new(),
+            samples_read: 0,
+            integrator: 0.,
+        }
+    }
+
+    // Adds a change in amplitude at the given clock time
+    pub fn add_delta(&mut self, time: u64, delta: f32) {
+        if delta == 0. {
+            return;
+        }
+
+        let position = time as f64 / self.clocks_per_sample - self.samples_read as f64;
+        let index = position as usize;
+        let phase = ((position - index as f64) * KERNEL_PHASES as f64) as usize;
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.);
+        }
+
+        for (sample, weight) in self.deltas[index..].iter_mut().zip(self.kernel[phase]) {
+            *sample += delta * weight;
+        }
+    }
+
+    // Reads out every sample before the given clock time, later deltas can't change them
+    pub fn read_samples(&mut self, time: u64, samples: &mut Vec<f32>) {
+        let sample_count =
+            ((time as f64 / self.clocks_per_sample) as u64).saturating_sub(self.samples_read);
+        let sample_count = sample_count as usize;
+
+        if self.deltas.len() < sample_count {
+            self.deltas.resize(sample_count, 0.);
+        }
+
+        for delta in self.deltas.drain(..sample_count) {
+            self.integrator += delta;
+            samples.push(self.integrator);
+        }
+
+        self.samples_read += sample_count as u64;
+    }
+}
+
+// Windowed sinc impulse for a step a fraction of a sample after the start of the kernel's middle
+// sample, normalized so the step has the full height
+fn get_kernel_phase(phase: usize) -> [f32; KERNEL_WIDTH] {
+    let half_width = (KERNEL_WIDTH / 2) as f64;
+    let center = half_width - 1. + phase as f64 / KERNEL_PHASES as f64;
+
+    let mut taps = [0.; KERNEL_WIDTH];
+    for (tap, weight) in taps.iter_mut().enumerate() {
+        let x = tap as f64 - center;
+        let sinc = if x == 0. {
+            1.
+        } else {
+            (PI * x * CUTOFF).sin() / (PI * x * CUTOFF)
+        };
+        // Blackman window
+        let t = x / half_width;
+        let window = if t.abs() >= 1. {
+            0.
+        } else {
+            0.42 + 0.5 * (PI * t).cos() + 0.08 * (2. * PI * t).cos()
+        };
+
+        *weight = sinc * window;
+    }
+
+    let sum: f64 = taps.iter().sum();
+    taps.map(|weight| (weight / sum) as f32)
+}
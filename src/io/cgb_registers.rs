@@ -1,26 +1,50 @@
 pub struct CGBRegisters {
-    key_1: u8,
+    key_1: u8, // Bit 7 is the current speed, bit 0 switches speed on the next STOP
+    object_priority_mode: u8, // OPRI, bit 0 clear selects OAM index priority
 }
 
 impl CGBRegisters {
     pub fn default() -> CGBRegisters {
-        CGBRegisters { key_1: 0 }
+        CGBRegisters {
+            key_1: 0,
+            object_priority_mode: 0,
+        }
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
         match address {
-            0xFF4D => 0xFF, // TODO: Implement CGB Speed Switching -- This is a temp value because blargg's DMG guards are buggy
+            0xFF4D => self.key_1,
+            0xFF6C => self.object_priority_mode,
             _ => panic!("Unimplemented CGB Register Read address: 0x{:04X}", address),
         }
     }
 
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match address {
-            0xFF4D => panic!("CGB Speed Switching not implemented"),
+            0xFF4D => self.key_1 = (self.key_1 & 0x80) | (value & 0x01),
+            0xFF6C => self.object_priority_mode = value & 0x01,
             _ => panic!(
                 "Unimplemented CGB Register Write address: 0x{:04X} -- 0x{:02X}",
                 address, value
             ),
         }
     }
+
+    pub fn is_speed_switch_requested(&self) -> bool {
+        self.key_1 & 0x01 != 0
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.key_1 & 0x80 != 0
+    }
+
+    pub fn switch_speed(&mut self) {
+        self.key_1 = (self.key_1 ^ 0x80) & 0x80;
+    }
+
+    // CGB resolves overlapping objects by OAM index, the boot ROM selects DMG priority (lower X
+    // first) for DMG games
+    pub fn is_oam_index_priority(&self) -> bool {
+        self.object_priority_mode & 0x01 == 0
+    }
 }
@@ -0,0 +1,341 @@
+// Colors the CGB boot ROM gives DMG games, which only have the DMG palette registers. The boot ROM
+// loads them into background palette 0 and object palettes 0 and 1, then the DMG palette
+// registers pick from those instead of the 4 shades
+
+#[derive(Copy, Clone)]
+pub struct CompatibilityPalettes {
+    pub bg: [u16; 4],
+    pub obj_0: [u16; 4],
+    pub obj_1: [u16; 4],
+}
+
+// Button combinations held during the boot animation override the palettes picked by title
+#[derive(Copy, Clone, Debug)]
+pub enum ManualPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ManualPalette {
+    pub fn from_name(name: &str) -> Option<ManualPalette> {
+        match name.to_lowercase().as_str() {
+            "up" => Some(ManualPalette::Up),
+            "up+a" => Some(ManualPalette::UpA),
+            "up+b" => Some(ManualPalette::UpB),
+            "left" => Some(ManualPalette::Left),
+            "left+a" => Some(ManualPalette::LeftA),
+            "left+b" => Some(ManualPalette::LeftB),
+            "down" => Some(ManualPalette::Down),
+            "down+a" => Some(ManualPalette::DownA),
+            "down+b" => Some(ManualPalette::DownB),
+            "right" => Some(ManualPalette::Right),
+            "right+a" => Some(ManualPalette::RightA),
+            "right+b" => Some(ManualPalette::RightB),
+            _ => None,
+        }
+    }
+
+    pub fn get_palettes(&self) -> CompatibilityPalettes {
+        let combination = match self {
+            ManualPalette::Up => 5,
+            ManualPalette::UpA => 43,
+            ManualPalette::UpB => 28,
+            ManualPalette::Left => 48,
+            ManualPalette::LeftA => 40,
+            ManualPalette::LeftB => 7,
+            ManualPalette::Down => 8,
+            ManualPalette::DownA => 3,
+            ManualPalette::DownB => 49,
+            ManualPalette::Right => 1,
+            ManualPalette::RightA => 0,
+            ManualPalette::RightB => 6,
+        };
+
+        get_combination(combination)
+    }
+}
+
+// Picks the palettes for a game from the sum of its title bytes. Checksums shared by several
+// games are told apart by the 4th letter of the title, each of them has a row of letters to check
+// in turn. Games from other licensees, and unknown ones, get the default palettes
+pub fn get_title_palettes(title_checksum: Option<(u8, u8)>) -> CompatibilityPalettes {
+    let combination = title_checksum
+        .and_then(|(checksum, fourth_letter)| {
+            let index = TITLE_CHECKSUMS
+                .iter()
+                .position(|entry_checksum| *entry_checksum == checksum)?;
+
+            if index < FIRST_SHARED_CHECKSUM {
+                return Some(index);
+            }
+
+            (index..CHECKSUM_COMBINATIONS.len())
+                .step_by(SHARED_CHECKSUM_COUNT)
+                .find(|index| FOURTH_LETTERS[index - FIRST_SHARED_CHECKSUM] == fourth_letter)
+        })
+        .map_or(DEFAULT_COMBINATION, |index| CHECKSUM_COMBINATIONS[index]);
+
+    get_combination(combination)
+}
+
+fn get_combination(index: usize) -> CompatibilityPalettes {
+    let (obj_0, obj_1, bg) = PALETTE_COMBINATIONS[index];
+    let get_palette = |offset: usize| {
+        let mut palette = [0; 4];
+        palette.copy_from_slice(&PALETTE_COLORS[offset..(offset + 4)]);
+        palette
+    };
+
+    CompatibilityPalettes {
+        bg: get_palette(bg),
+        obj_0: get_palette(obj_0),
+        obj_1: get_palette(obj_1),
+    }
+}
+
+const DEFAULT_COMBINATION: usize = 0;
+
+// Offset of a palette in PALETTE_COLORS
+const fn palette(index: usize) -> usize {
+    index * 4
+}
+
+// RGB555 colors of the boot ROM's palettes, 4 colors each from lightest to darkest
+#[rustfmt::skip]
+const PALETTE_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Offsets into PALETTE_COLORS of object palette 0, object palette 1 and the background palette.
+// A few combinations start in the middle of a palette
+const PALETTE_COMBINATIONS: [(usize, usize, usize); 51] = [
+    (palette(4), palette(4), palette(29)),
+    (palette(18), palette(18), palette(18)),
+    (palette(20), palette(20), palette(20)),
+    (palette(24), palette(24), palette(24)),
+    (palette(9), palette(9), palette(9)),
+    (palette(0), palette(0), palette(0)), // 5
+    (palette(27), palette(27), palette(27)),
+    (palette(5), palette(5), palette(5)),
+    (palette(12), palette(12), palette(12)),
+    (palette(26), palette(26), palette(26)),
+    (palette(16), palette(8), palette(8)), // 10
+    (palette(4), palette(28), palette(28)),
+    (palette(4), palette(2), palette(2)),
+    (palette(3), palette(4), palette(4)),
+    (palette(4), palette(29), palette(29)),
+    (palette(28), palette(4), palette(28)), // 15
+    (palette(2), palette(17), palette(2)),
+    (palette(16), palette(16), palette(8)),
+    (palette(4), palette(4), palette(7)),
+    (palette(4), palette(4), palette(18)),
+    (palette(4), palette(4), palette(20)), // 20
+    (palette(19), palette(19), palette(9)),
+    (palette(4) - 1, palette(4) - 1, palette(11)),
+    (palette(17), palette(17), palette(2)),
+    (palette(4), palette(4), palette(2)),
+    (palette(4), palette(4), palette(3)), // 25
+    (palette(28), palette(28), palette(0)),
+    (palette(3), palette(3), palette(0)),
+    (palette(0), palette(0), palette(1)),
+    (palette(18), palette(22), palette(18)),
+    (palette(20), palette(22), palette(20)), // 30
+    (palette(24), palette(22), palette(24)),
+    (palette(16), palette(22), palette(8)),
+    (palette(17), palette(4), palette(13)),
+    (palette(28) - 1, palette(0), palette(14)),
+    (palette(28) - 1, palette(4), palette(15)), // 35
+    (palette(19), palette(22), palette(9)),
+    (palette(16), palette(28), palette(10)),
+    (palette(4), palette(23), palette(28)),
+    (palette(17), palette(22), palette(2)),
+    (palette(4), palette(0), palette(2)), // 40
+    (palette(4), palette(28), palette(3)),
+    (palette(28), palette(3), palette(0)),
+    (palette(3), palette(28), palette(4)),
+    (palette(21), palette(28), palette(4)),
+    (palette(3), palette(28), palette(0)), // 45
+    (palette(25), palette(3), palette(28)),
+    (palette(0), palette(28), palette(8)),
+    (palette(4), palette(3), palette(28)),
+    (palette(28), palette(3), palette(6)),
+    (palette(4), palette(28), palette(29)), // 50
+];
+
+const FIRST_SHARED_CHECKSUM: usize = 65;
+const SHARED_CHECKSUM_COUNT: usize = 14;
+
+// Title checksums of Nintendo's games, the last 14 are shared by several games
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, // Default
+    0x88, // ALLEY WAY
+    0x16, // YAKUMAN
+    0x36, // BASEBALL
+    0xD1, // TENNIS
+    0xDB, // TETRIS
+    0xF2, // QIX
+    0x3C, // DR.MARIO
+    0x8C, // RADARMISSION
+    0x92, // F1RACE
+    0x3D, // YOSSY NO TAMAGO
+    0x5C,
+    0x58, // X
+    0xC9, // MARIOLAND2
+    0x3E, // YOSSY NO COOKIE
+    0x70, // ZELDA
+    0x1D,
+    0x59,
+    0x69, // TETRIS FLASH
+    0x19, // DONKEY KONG
+    0x35, // MARIO'S PICROSS
+    0xA8,
+    0x14, // POKEMON RED
+    0xAA, // POKEMON GREEN
+    0x75, // PICROSS 2
+    0x95, // YOSSY NO PANEPON
+    0x99, // KIRAKIRA KIDS
+    0x34, // GAMEBOY GALLERY
+    0x6F, // POCKETCAMERA
+    0x15,
+    0xFF, // BALLOON KID
+    0x97, // KINGOFTHEZOO
+    0x4B, // DMG FOOTBALL
+    0x90, // WORLD CUP
+    0x17, // OTHELLO
+    0x10, // SUPER RC PRO-AM
+    0x39, // DYNABLASTER
+    0xF7, // BOY AND BLOB GB2
+    0xF6, // MEGAMAN
+    0xA2, // STAR WARS-NOA
+    0x49,
+    0x4E, // WAVERACE
+    0x43,
+    0x68, // LOLO2
+    0xE0, // YOSHI'S COOKIE
+    0x8B, // MYSTIC QUEST
+    0xF0,
+    0xCE, // TOPRANKINGTENNIS
+    0x0C, // MANSELL
+    0x29, // MEGAMAN3
+    0xE8, // SPACE INVADERS
+    0xB7, // GAME&WATCH
+    0x86, // DONKEYKONGLAND95
+    0x9A, // ASTEROIDS/MISCMD
+    0x52, // STREET FIGHTER 2
+    0x01, // DEFENDER/JOUST
+    0x9D, // KILLERINSTINCT95
+    0x71, // TETRIS BLAST
+    0x9C, // PINOCCHIO
+    0xBD,
+    0x5D, // BA.TOSHINDEN
+    0x6D, // NETTOU KOF 95
+    0x67,
+    0x3F, // TETRIS PLUS
+    0x6B, // DONKEYKONGLAND 3
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+// 4th letters of the titles sharing a checksum, in rows lined up with the shared checksums
+const FOURTH_LETTERS: [u8; 29] = *b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination of each title checksum, followed by one for each 4th letter
+#[rustfmt::skip]
+const CHECKSUM_COMBINATIONS: [usize; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, // 0
+    19, 36, 7, 37, 30, 44, 21, 32, 31, 20, // 10
+    5, 33, 13, 14, 5, 29, 5, 18, 9, 3, // 20
+    2, 26, 25, 25, 41, 42, 26, 45, 42, 45, // 30
+    36, 38, 26, 42, 30, 41, 34, 34, 5, 42, // 40
+    6, 5, 33, 25, 42, 42, 40, 14, 16, 46, // 50
+    7, 7, 7, 16, 17, // 60
+    // B: ?, E: SUPER MARIOLAND, F: GOLF, A: SOLARSTRIKER, A: GBWARS, R: KAERUNOTAMENI, B: ?,
+    // E: POKEMON BLUE, K: DONKEYKONGLAND, E: GAMEBOY GALLERY2, K: DONKEYKONGLAND 2,
+    // space: KID ICARUS, R: TETRIS2, -: ?
+    37, 32, 31, 30, 19, 2, 17, 11, 21, 29, 10, 8, 15, 13,
+    // U: MOGURANYA, R: TETRIS ATTACK, A: GALAGA&GALAXIAN, R: BT2RAGNAROKWORLD,
+    // space: KEN GRIFFEY JR, I: ?, N: MAGNETIC SOCCER, A: VEGAS STAKES, I: ?,
+    // L: MILLI/CENTI/PEDE, I: MARIO & YOSHI, C: SOCCER, E: POKEBOM, space: ?
+    15, 3, 28, 5, 23, 27, 19, 12, 36, 6, 6, 15, 25, 24,
+    // R: ?
+    1,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{get_combination, get_title_palettes, DEFAULT_COMBINATION};
+
+    fn assert_combination(title_checksum: Option<(u8, u8)>, combination: usize) {
+        let palettes = get_title_palettes(title_checksum);
+        let expected = get_combination(combination);
+
+        assert_eq!(palettes.bg, expected.bg);
+        assert_eq!(palettes.obj_0, expected.obj_0);
+        assert_eq!(palettes.obj_1, expected.obj_1);
+    }
+
+    #[test]
+    fn unique_checksums_ignore_the_fourth_letter() {
+        assert_combination(Some((0xDB, b'R')), 3); // TETRIS
+        assert_combination(Some((0xDB, b'X')), 3);
+        assert_combination(Some((0x6B, b'K')), 17); // DONKEYKONGLAND 3
+    }
+
+    #[test]
+    fn shared_checksums_pick_the_row_with_the_fourth_letter() {
+        assert_combination(Some((0x46, b'E')), 32); // SUPER MARIOLAND
+        assert_combination(Some((0x46, b'R')), 3); // TETRIS ATTACK
+        assert_combination(Some((0x61, b'E')), 11); // POKEMON BLUE
+        assert_combination(Some((0x61, b'A')), 12); // VEGAS STAKES
+        assert_combination(Some((0xB3, b'B')), 37);
+        assert_combination(Some((0xB3, b'U')), 15); // MOGURANYA
+        assert_combination(Some((0xB3, b'R')), 1);
+    }
+
+    #[test]
+    fn unknown_games_get_the_default_palettes() {
+        assert_combination(None, DEFAULT_COMBINATION);
+        assert_combination(Some((0x02, b'A')), DEFAULT_COMBINATION);
+        assert_combination(Some((0x46, b'X')), DEFAULT_COMBINATION);
+        assert_combination(Some((0xF4, b'R')), DEFAULT_COMBINATION);
+    }
+}
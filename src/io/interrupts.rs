@@ -63,6 +63,14 @@ impl Interrupt {
         (self.interrupt_enable & self.interrupt_flag) & 0x02 != 0
     }
 
+    pub fn is_serial_interrupt(&self) -> bool {
+        (self.interrupt_enable & self.interrupt_flag) & 0x08 != 0
+    }
+
+    pub fn is_joypad_interrupt(&self) -> bool {
+        (self.interrupt_enable & self.interrupt_flag) & 0x10 != 0
+    }
+
     // Interrupt Setters
 
     pub fn disable_timer_interrupt(&mut self) {
@@ -88,4 +96,16 @@ impl Interrupt {
     pub fn disable_lcd_interrupt(&mut self) {
         self.interrupt_flag &= !0x02;
     }
+
+    pub fn set_serial_interrupt(&mut self) {
+        self.interrupt_flag |= 0x08;
+    }
+
+    pub fn disable_serial_interrupt(&mut self) {
+        self.interrupt_flag &= !0x08;
+    }
+
+    pub fn disable_joypad_interrupt(&mut self) {
+        self.interrupt_flag &= !0x10;
+    }
 }
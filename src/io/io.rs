@@ -1,6 +1,7 @@
 use super::{
     cgb_registers::CGBRegisters, interrupts::Interrupt, joypad::Joypad, lcd::LCD,
-    oam::ObjectAttributeMemory, serial::Serial, sound::Sound, timer::Timer, vram::VRam,
+    oam::ObjectAttributeMemory, serial::Serial, sgb::SuperGameBoy, sound::Sound, timer::Timer,
+    vram::VRam,
 };
 
 enum IOMap {
@@ -13,29 +14,55 @@ enum IOMap {
     Serial,
     CGBRegisters,
     Joypad,
-    Unused, // Illegal writes to unused memory
-    Unimplemented,
+    Unused, // Unmapped registers, reads are open bus and writes are ignored
 }
 
 impl IOMap {
     fn parse_address(address: u16) -> IOMap {
         match address {
             0xFF01 | 0xFF02 => IOMap::Serial,
-            0xFF4F | 0x8000..=0x9FFF => IOMap::VRam,
+            0xFF4F | 0xFF51..=0xFF55 | 0x8000..=0x9FFF => IOMap::VRam,
             0xFF46 | 0xFE00..=0xFE9F => IOMap::OAM,
             0xFF04..=0xFF07 => IOMap::Timer,
             0xFF10..=0xFF26 | 0xFF30..=0xFF3F => IOMap::Sound,
-            0xFF40..=0xFF4B => IOMap::LCD,
-            0xFF4D => IOMap::CGBRegisters,
+            0xFF40..=0xFF4B | 0xFF68..=0xFF6B => IOMap::LCD,
+            0xFF4D | 0xFF6C => IOMap::CGBRegisters,
             0xFF0F => IOMap::Interrupt,
             0xFFFF => IOMap::Interrupt,
             0xFF00 => IOMap::Joypad,
-            0xFF7F => IOMap::Unused,
-            _ => IOMap::Unimplemented,
+            _ => IOMap::Unused,
         }
     }
 }
 
+// Bits that always read back as 1, indexed by register address - 0xFF00
+// Unmapped registers read back as 0xFF, write only registers read back as 0xFF
+#[rustfmt::skip]
+const DMG_READ_MASKS: [u8; 0x80] = [
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0, // 0xFF00
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF, // 0xFF10
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF20
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xFF30
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF40
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF50
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF60
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF70
+];
+
+// CGB adds the serial clock speed bit and the CGB only registers (KEY1, VBK, HDMA, palettes and
+// OPRI). SVBK is read through WRAM, RP and the undocumented 0xFF72-0xFF77 aren't mapped
+#[rustfmt::skip]
+const CGB_READ_MASKS: [u8; 0x80] = [
+    0xC0, 0x00, 0x7C, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0, // 0xFF00
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF, // 0xFF10
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF20
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xFF30
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x7E, 0xFF, 0xFE, // 0xFF40
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF50
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x40, 0x00, 0x40, 0x00, 0xFE, 0xFF, 0xFF, 0xFF, // 0xFF60
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // 0xFF70
+];
+
 pub struct IO {
     pub timer: Timer,
     pub interrupt: Interrupt,
@@ -46,6 +73,8 @@ pub struct IO {
     pub cgb_registers: CGBRegisters,
     pub oam: ObjectAttributeMemory,
     pub joypad: Joypad,
+    pub sgb: SuperGameBoy,
+    pub cgb_mode: bool,
 }
 
 impl IO {
@@ -60,9 +89,23 @@ impl IO {
             cgb_registers: CGBRegisters::default(),
             oam: ObjectAttributeMemory::default(),
             joypad: Joypad::default(),
+            sgb: SuperGameBoy::default(),
+            cgb_mode: false,
         }
     }
 
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.timer.cgb_mode = cgb_mode;
+        self.lcd.cgb_mode = cgb_mode;
+        self.vram.cgb_mode = cgb_mode;
+        self.sound.cgb_mode = cgb_mode;
+    }
+
+    pub fn is_mapped(&self, address: u16) -> bool {
+        !matches!(IOMap::parse_address(address), IOMap::Unused)
+    }
+
     pub fn write_u8(&mut self, address: u16, value: u8) {
         let io_map = IOMap::parse_address(address);
         match io_map {
@@ -79,7 +122,7 @@ impl IO {
                 self.sound.write_u8(address, value);
             }
             IOMap::LCD => {
-                self.lcd.write_u8(address, value);
+                self.lcd.write_u8(address, value, &mut self.interrupt);
             }
             IOMap::VRam => {
                 self.vram.write_u8(address, value);
@@ -91,19 +134,34 @@ impl IO {
                 self.cgb_registers.write_u8(address, value);
             }
             IOMap::Joypad => {
-                self.joypad.write_u8(address, value);
+                self.joypad.write_u8(address, value, &mut self.sgb);
             }
             IOMap::Unused => {
                 // Do nothing
             }
-            _ => panic!(
-                "Unimplemented IO Write 0x{:04X} with value {:02X}",
-                address, value
-            ),
         }
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
+        let value = self.read_register_u8(address);
+
+        match address {
+            0xFF00..=0xFF7F => value | self.read_mask(address),
+            _ => value,
+        }
+    }
+
+    fn read_mask(&self, address: u16) -> u8 {
+        let index = (address - 0xFF00) as usize;
+
+        if self.cgb_mode {
+            CGB_READ_MASKS[index]
+        } else {
+            DMG_READ_MASKS[index]
+        }
+    }
+
+    fn read_register_u8(&self, address: u16) -> u8 {
         let io_map = IOMap::parse_address(address);
         match io_map {
             IOMap::Serial => self.serial.read_u8(address),
@@ -114,9 +172,96 @@ impl IO {
             IOMap::Interrupt => self.interrupt.read_u8(address),
             IOMap::Sound => self.sound.read_u8(address),
             IOMap::CGBRegisters => self.cgb_registers.read_u8(address),
-            IOMap::Joypad => self.joypad.read_u8(address),
+            IOMap::Joypad => self.joypad.read_u8(address, &self.sgb),
             IOMap::Unused => 0xFF, // Unused memory returns 0xFF
-            _ => panic!("Unimplemented IO Read 0x{:04X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IO;
+
+    // Documented read-back values after writing 0x00 and 0xFF to each register of a freshly booted
+    // DMG, anything not listed reads 0xFF either way
+    #[rustfmt::skip]
+    const DMG_READ_BACK: [(u16, u8, u8); 52] = [
+        (0xFF00, 0xCF, 0xFF), // P1, no buttons pressed
+        (0xFF01, 0x00, 0xFF), (0xFF02, 0x7E, 0xFF), // SB, SC
+        (0xFF04, 0x00, 0x00), // DIV resets on any write
+        (0xFF05, 0x00, 0xFF), (0xFF06, 0x00, 0xFF), (0xFF07, 0xF8, 0xFF), // TIMA, TMA, TAC
+        (0xFF0F, 0xE0, 0xFF), // IF
+        (0xFF10, 0x80, 0xFF), (0xFF11, 0x3F, 0xFF), (0xFF12, 0x00, 0xFF), (0xFF14, 0xBF, 0xFF),
+        (0xFF16, 0x3F, 0xFF), (0xFF17, 0x00, 0xFF), (0xFF19, 0xBF, 0xFF),
+        (0xFF1A, 0x7F, 0xFF), (0xFF1C, 0x9F, 0xFF), (0xFF1E, 0xBF, 0xFF),
+        (0xFF21, 0x00, 0xFF), (0xFF22, 0x00, 0xFF), (0xFF23, 0xBF, 0xFF),
+        (0xFF24, 0x00, 0xFF), (0xFF25, 0x00, 0xFF),
+        (0xFF26, 0x70, 0xF0), // NR52, no channel is playing yet
+        (0xFF30, 0x00, 0xFF), (0xFF31, 0x00, 0xFF), (0xFF32, 0x00, 0xFF), (0xFF33, 0x00, 0xFF),
+        (0xFF34, 0x00, 0xFF), (0xFF35, 0x00, 0xFF), (0xFF36, 0x00, 0xFF), (0xFF37, 0x00, 0xFF),
+        (0xFF38, 0x00, 0xFF), (0xFF39, 0x00, 0xFF), (0xFF3A, 0x00, 0xFF), (0xFF3B, 0x00, 0xFF),
+        (0xFF3C, 0x00, 0xFF), (0xFF3D, 0x00, 0xFF), (0xFF3E, 0x00, 0xFF), (0xFF3F, 0x00, 0xFF),
+        (0xFF40, 0x00, 0xFF), // LCDC
+        (0xFF41, 0x85, 0xFD), // STAT, the mode and LYC flag are read only
+        (0xFF42, 0x00, 0xFF), (0xFF43, 0x00, 0xFF), // SCY, SCX
+        (0xFF44, 0x00, 0x00), // LY is read only
+        (0xFF45, 0x00, 0xFF), (0xFF46, 0x00, 0xFF), // LYC, DMA
+        (0xFF47, 0x00, 0xFF), (0xFF48, 0x00, 0xFF), (0xFF49, 0x00, 0xFF), // BGP, OBP0, OBP1
+        (0xFF4A, 0x00, 0xFF), (0xFF4B, 0x00, 0xFF), // WY, WX
+    ];
+
+    // CGB registers on top of the DMG ones, and the serial clock speed bit in SC
+    #[rustfmt::skip]
+    const CGB_READ_BACK: [(u16, u8, u8); 9] = [
+        (0xFF02, 0x7C, 0xFF), // SC
+        (0xFF4D, 0x7E, 0x7F), // KEY1, only the switch request is writable
+        (0xFF4F, 0xFE, 0xFF), // VBK
+        (0xFF55, 0xFF, 0x7F), // HDMA5, a general DMA is done right away, an HBlank DMA is left running
+        (0xFF68, 0x40, 0xFF), (0xFF69, 0x00, 0xFF), // BCPS, BCPD
+        (0xFF6A, 0x40, 0xFF), (0xFF6B, 0x00, 0xFF), // OCPS, OCPD
+        (0xFF6C, 0xFE, 0xFF), // OPRI
+    ];
+
+    fn get_expected_read_back(cgb_mode: bool, address: u16, value: u8) -> u8 {
+        let cgb_read_back = CGB_READ_BACK.iter().filter(|_| cgb_mode);
+
+        cgb_read_back
+            .chain(DMG_READ_BACK.iter())
+            .find(|(register, _, _)| *register == address)
+            .map_or(
+                0xFF,
+                |(_, zero, ones)| if value == 0x00 { *zero } else { *ones },
+            )
+    }
+
+    fn write_and_read_back(cgb_mode: bool, address: u16, value: u8) -> u8 {
+        let mut io = IO::default();
+        io.set_cgb_mode(cgb_mode);
+        io.write_u8(address, value);
+
+        // The bus copies a general DMA as soon as it is requested
+        while io.vram.is_general_dma_requested {
+            io.vram.advance_dma();
+        }
+
+        io.read_u8(address)
+    }
+
+    #[test]
+    fn registers_read_back_documented_values() {
+        for cgb_mode in [false, true] {
+            for address in 0xFF00..=0xFF7F {
+                for value in [0x00, 0xFF] {
+                    assert_eq!(
+                        write_and_read_back(cgb_mode, address, value),
+                        get_expected_read_back(cgb_mode, address, value),
+                        "0x{:04X} after writing 0x{:02X}, CGB: {}",
+                        address,
+                        value,
+                        cgb_mode
+                    );
+                }
+            }
         }
     }
 }
@@ -1,3 +1,5 @@
+use super::sgb::SuperGameBoy;
+
 pub struct JoyPadButtons {
     pub right: bool,
     pub left: bool,
@@ -135,16 +137,29 @@ impl Joypad {
         button_state
     }
 
-    pub fn read_u8(&self, address: u16) -> u8 {
+    pub fn read_u8(&self, address: u16, sgb: &SuperGameBoy) -> u8 {
+        let selection = self.joypad_state & 0x30;
+
         match address {
-            0xFF00 => self.read_joypad_buttons(),
+            0xFF00 => match sgb.get_joypad_id() {
+                // With several SGB joypads, deselecting both button groups reads the current ID
+                Some(joypad_id) if selection == 0x30 => selection | (0x0F - joypad_id),
+                // Only the first joypad has buttons pressed
+                Some(joypad_id) if joypad_id != 0 => selection | 0x0F,
+                _ => selection | self.read_joypad_buttons(),
+            },
             _ => panic!("Invalid Joypad Read address: 0x{:04X}", address),
         }
     }
 
-    pub fn write_u8(&mut self, address: u16, value: u8) {
+    pub fn write_u8(&mut self, address: u16, value: u8, sgb: &mut SuperGameBoy) {
         match address {
-            0xFF00 => self.joypad_state = (self.joypad_state & 0x0F) | (value & 0xF0),
+            0xFF00 => {
+                self.joypad_state = (self.joypad_state & 0x0F) | (value & 0xF0);
+
+                // The SGB receives packets and switches joypads through the selection lines
+                sgb.write_joypad_lines(value & 0x30);
+            }
             _ => panic!("Invalid Joypad Write address: 0x{:04X}", address),
         }
     }
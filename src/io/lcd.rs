@@ -1,4 +1,11 @@
-use super::interrupts::{self, Interrupt};
+use super::{compatibility_palettes::CompatibilityPalettes, interrupts::Interrupt};
+
+pub const SCANLINE_DOTS: u64 = 456;
+pub const FRAME_DOTS: u64 = SCANLINE_DOTS * 154;
+const MODE_2_DOTS: u64 = 80;
+const FIRST_LINE_MODE_0_DOTS: u64 = MODE_2_DOTS - 4; // The first line after turning on is shorter
+const MODE_3_MIN_DOTS: u64 = 172; // Mode 3 ends once the PPU has drawn the whole scanline
+const LINE_153_LY_DOTS: u64 = 4; // LY reads 0 for all but the start of line 153
 
 pub enum ScanLineEvent {
     OAMScanEntered,
@@ -17,15 +24,6 @@ pub struct PaletteData {
 }
 
 impl PaletteData {
-    pub fn default() -> PaletteData {
-        PaletteData {
-            color_0: 0,
-            color_1: 0,
-            color_2: 0,
-            color_3: 0,
-        }
-    }
-
     pub fn get_color(&self, color: u8) -> u8 {
         match color {
             0 => self.color_0,
@@ -36,16 +34,6 @@ impl PaletteData {
         }
     }
 
-    pub fn get_object_color(&self, color: u8) -> u8 {
-        match color {
-            0 => 4,
-            1 => self.color_1,
-            2 => self.color_2,
-            3 => self.color_3,
-            _ => panic!("Invalid color: {}", color),
-        }
-    }
-
     pub fn from_u8(byte: u8) -> PaletteData {
         let color_0 = byte & 0x03;
         let color_1 = (byte & 0x0C) >> 2;
@@ -61,19 +49,80 @@ impl PaletteData {
     }
 }
 
+// CGB color palette RAM, 8 palettes of 4 RGB555 colors accessed through an index register
+// 0xFF68/0xFF6A and a data register 0xFF69/0xFF6B
+pub struct ColorPaletteRam {
+    data: [u8; 64],
+    index: u8, // Bit 7 increments the index after every data write
+}
+
+impl ColorPaletteRam {
+    pub fn default() -> ColorPaletteRam {
+        ColorPaletteRam {
+            data: [0xFF; 64], // White
+            index: 0,
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        self.index
+    }
+
+    pub fn write_index(&mut self, value: u8) {
+        self.index = value & 0xBF;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[(self.index & 0x3F) as usize]
+    }
+
+    // The index still increments when the write is blocked by the PPU
+    pub fn write_data(&mut self, value: u8, is_accessible: bool) {
+        if is_accessible {
+            self.data[(self.index & 0x3F) as usize] = value;
+        }
+
+        if self.index & 0x80 != 0 {
+            self.index = 0x80 | (self.index.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: u8, colors: [u16; 4]) {
+        for (color, value) in colors.iter().enumerate() {
+            let address = (palette as usize * 4 + color) * 2;
+            self.data[address] = *value as u8;
+            self.data[address + 1] = (*value >> 8) as u8;
+        }
+    }
+
+    pub fn get_color(&self, palette: u8, color: u8) -> u16 {
+        let address = (palette as usize * 4 + color as usize) * 2;
+
+        ((self.data[address + 1] as u16) << 8 | self.data[address] as u16) & 0x7FFF
+    }
+}
+
 pub struct LCD {
     pub lcd_control: u8,      // 0xFF40
     pub lcd_status: u8,       // 0xFF41
     scroll_y: u8,             // 0xFF42
     scroll_x: u8,             // 0xFF43
     pub lcd_y_coordinate: u8, // 0xFF44
-    lcd_y_cycles: u16,
-    ly_compare: u8,    // 0xFF45
-    bg_palette: u8,    // 0xFF47
-    obj_palette_0: u8, // 0xFF48
-    obj_palette_1: u8, // 0xFF49
-    window_x: u8,      // 0xFF4A
-    window_y: u8,      // 0xFF4B
+    scanline: u8,             // Line currently being drawn, can differ from LY
+    ly_compare: u8,           // 0xFF45
+    bg_palette: u8,           // 0xFF47
+    obj_palette_0: u8,        // 0xFF48
+    obj_palette_1: u8,        // 0xFF49
+    window_x: u8,             // 0xFF4A
+    window_y: u8,             // 0xFF4B
+    // The STAT sources are ORed together, only a rising edge requests an interrupt
+    stat_interrupt_line: bool,
+    is_on: bool, // Follows LCDC bit 7, but only once the bus has started or stopped the PPU
+    is_first_line: bool, // The first line after turning on has no OAM scan mode
+    pub cgb_mode: bool,
+    bg_color_palettes: ColorPaletteRam,  // 0xFF68 - 0xFF69
+    obj_color_palettes: ColorPaletteRam, // 0xFF6A - 0xFF6B
+    pub dmg_compatibility: bool,         // DMG game on a CGB, colored through the color palettes
 }
 
 impl LCD {
@@ -84,13 +133,20 @@ impl LCD {
             scroll_y: 0,
             scroll_x: 0,
             lcd_y_coordinate: 0,
-            lcd_y_cycles: 0,
+            scanline: 153, // Boot hands over at the end of the frame
             ly_compare: 0,
             bg_palette: 0xFC, // 0b11111100
             obj_palette_0: 0xFF,
             obj_palette_1: 0xFF,
             window_x: 0,
             window_y: 0,
+            stat_interrupt_line: false,
+            is_on: true,
+            is_first_line: false,
+            cgb_mode: false,
+            bg_color_palettes: ColorPaletteRam::default(),
+            obj_color_palettes: ColorPaletteRam::default(),
+            dmg_compatibility: false,
         }
     }
 
@@ -107,23 +163,54 @@ impl LCD {
             0xFF49 => self.obj_palette_1,
             0xFF4A => self.window_y,
             0xFF4B => self.window_x,
+            0xFF68 => self.bg_color_palettes.read_index(),
+            0xFF6A => self.obj_color_palettes.read_index(),
+            0xFF69 | 0xFF6B if !self.is_palette_accessible() => 0xFF,
+            0xFF69 => self.bg_color_palettes.read_data(),
+            0xFF6B => self.obj_color_palettes.read_data(),
             _ => panic!("Invalid LCD Read address: 0x{:04X}", address),
         }
     }
 
-    pub fn write_u8(&mut self, address: u16, value: u8) {
+    pub fn write_u8(&mut self, address: u16, value: u8, interrupt: &mut Interrupt) {
         match address {
             0xFF40 => self.lcd_control = value,
-            0xFF41 => self.lcd_status = value,
+            0xFF41 => {
+                // DMG bug, the LYC (bit 6), VBlank (bit 4) and HBlank (bit 3) sources are briefly
+                // enabled while STAT is written, the OAM source (bit 5) isn't
+                if !self.cgb_mode {
+                    self.lcd_status |= 0x40 | 0x10 | 0x08;
+                    self.update_stat_interrupt_line(interrupt);
+                }
+
+                self.lcd_status = (self.lcd_status & 0x07) | (value & 0x78); // Mode and LYC flag are read only
+                self.update_stat_interrupt_line(interrupt);
+            }
             0xFF42 => self.scroll_y = value,
             0xFF43 => self.scroll_x = value,
             0xFF44 => self.lcd_y_coordinate = 0, // Writing to LY resets the value
-            0xFF45 => self.ly_compare = value,
+            0xFF45 => {
+                self.ly_compare = value;
+                self.update_ly_compare();
+                self.update_stat_interrupt_line(interrupt);
+            }
             0xFF47 => self.bg_palette = value,
             0xFF48 => self.obj_palette_0 = value,
             0xFF49 => self.obj_palette_1 = value,
             0xFF4A => self.window_y = value,
             0xFF4B => self.window_x = value,
+            // DMG has no color palettes
+            0xFF68..=0xFF6B if !self.cgb_mode => {}
+            0xFF68 => self.bg_color_palettes.write_index(value),
+            0xFF6A => self.obj_color_palettes.write_index(value),
+            0xFF69 => {
+                let is_accessible = self.is_palette_accessible();
+                self.bg_color_palettes.write_data(value, is_accessible);
+            }
+            0xFF6B => {
+                let is_accessible = self.is_palette_accessible();
+                self.obj_color_palettes.write_data(value, is_accessible);
+            }
             _ => panic!("Invalid LCD Write address: 0x{:04X}", address),
         }
     }
@@ -132,6 +219,32 @@ impl LCD {
         self.lcd_control & 0x80 != 0
     }
 
+    pub fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    // LY is held at 0 and the mode at HBlank while the LCD is off
+    pub fn turn_off(&mut self, interrupt: &mut Interrupt) {
+        self.is_on = false;
+        self.scanline = 0;
+        self.lcd_y_coordinate = 0;
+        self.set_mode_0();
+
+        self.update_ly_compare();
+        self.update_stat_interrupt_line(interrupt);
+    }
+
+    // Starts line 0 in HBlank, returns the number of dots until pixel transfer
+    pub fn turn_on(&mut self, interrupt: &mut Interrupt) -> u64 {
+        self.is_on = true;
+        self.is_first_line = true;
+
+        self.update_ly_compare();
+        self.update_stat_interrupt_line(interrupt);
+
+        FIRST_LINE_MODE_0_DOTS
+    }
+
     pub fn is_background_enabled(&self) -> bool {
         self.lcd_control & 0x01 != 0
     }
@@ -189,6 +302,25 @@ impl LCD {
         (self.window_y, self.window_x)
     }
 
+    pub fn get_mode(&self) -> u8 {
+        self.lcd_status & 0x03
+    }
+
+    // The PPU reads OAM during modes 2 and 3 and VRAM during mode 3, the CPU can't access them
+    // Locking follows the PPU, which only starts and stops once the bus handles the LCDC write
+    pub fn is_oam_accessible(&self) -> bool {
+        !self.is_on || self.get_mode() < 2
+    }
+
+    pub fn is_vram_accessible(&self) -> bool {
+        !self.is_on || self.get_mode() != 3
+    }
+
+    // Color palettes are read by the PPU during mode 3 like VRAM
+    pub fn is_palette_accessible(&self) -> bool {
+        self.is_vram_accessible()
+    }
+
     fn set_mode_2(&mut self) {
         self.lcd_status &= 0xFC;
         self.lcd_status |= 0x02;
@@ -203,94 +335,115 @@ impl LCD {
         self.lcd_status &= 0xFC;
     }
 
-    fn mode_2_lcd_interrupt(&self, interrupt: &mut Interrupt) -> () {
-        if self.lcd_status & 0x20 != 0 {
-            interrupt.set_lcd_interrupt();
-        }
+    fn set_mode_1(&mut self) {
+        self.lcd_status &= 0xFC;
+        self.lcd_status |= 0x01;
     }
 
-    fn mode_0_lcd_interrupt(&self, interrupt: &mut Interrupt) -> () {
-        if self.lcd_status & 0x08 != 0 {
-            interrupt.set_lcd_interrupt();
+    fn update_ly_compare(&mut self) {
+        if self.lcd_y_coordinate == self.ly_compare {
+            self.lcd_status |= 0x04;
+        } else {
+            self.lcd_status &= 0xFB;
         }
     }
 
-    fn is_before_vblank(&self) -> bool {
-        self.lcd_y_coordinate < 144
-    }
+    fn is_stat_interrupt_source_active(&self, is_vblank_start: bool) -> bool {
+        let mode = self.get_mode();
 
-    fn enters_mode_3(&self, previous_cycles: u16) -> bool {
-        self.lcd_y_cycles < 252 && previous_cycles < 80
+        (self.lcd_status & 0x08 != 0 && mode == 0)
+            || (self.lcd_status & 0x10 != 0 && mode == 1)
+            || (self.lcd_status & 0x20 != 0 && (mode == 2 || is_vblank_start))
+            || (self.lcd_status & 0x40 != 0 && self.lcd_status & 0x04 != 0)
     }
 
-    fn enters_mode_0(&self, previous_cycles: u16) -> bool {
-        self.lcd_y_cycles >= 252 && previous_cycles < 252
+    fn update_stat_interrupt_line(&mut self, interrupt: &mut Interrupt) {
+        self.set_stat_interrupt_line(self.is_stat_interrupt_source_active(false), interrupt);
     }
 
-    fn scanline_complete(&self) -> bool {
-        self.lcd_y_cycles >= 456
-    }
-
-    fn handle_ly_eq_ly_compare(&mut self, interrupt: &mut Interrupt) {
-        if self.lcd_y_coordinate == self.ly_compare {
-            self.lcd_status |= 0x04;
-
-            if self.lcd_status & 0x40 != 0 {
-                interrupt.set_lcd_interrupt();
-            }
-        } else {
-            self.lcd_status &= 0xFB;
+    fn set_stat_interrupt_line(&mut self, is_line_high: bool, interrupt: &mut Interrupt) {
+        if is_line_high && !self.stat_interrupt_line {
+            interrupt.set_lcd_interrupt();
         }
-    }
-
-    pub fn update_ly(&mut self, cycles: u8, interrupt: &mut Interrupt) -> ScanLineEvent {
-        let mut scanline_event = ScanLineEvent::None;
 
-        let previous_cycles = self.lcd_y_cycles;
-        self.lcd_y_cycles += cycles as u16 * 4; // T cycles?
+        self.stat_interrupt_line = is_line_high;
+    }
 
-        if self.is_before_vblank() {
-            if self.enters_mode_3(previous_cycles) {
-                // Mode 3
+    // Moves the PPU into its next mode, returning the event that occurred and the number of
+    // dots until the following mode change
+    pub fn update_mode(
+        &mut self,
+        interrupt: &mut Interrupt,
+        pixel_transfer_dots: u64,
+    ) -> (ScanLineEvent, u64) {
+        let (scanline_event, dots_until_next_mode) = match self.get_mode() {
+            2 => {
+                self.set_mode_3();
+                (ScanLineEvent::PixelTransferEntered, MODE_3_MIN_DOTS)
+            }
+            0 if self.is_first_line => {
+                self.is_first_line = false;
                 self.set_mode_3();
-                scanline_event = ScanLineEvent::PixelTransferEntered;
-            } else if self.enters_mode_0(previous_cycles) {
-                // Mode 0
+                (ScanLineEvent::PixelTransferEntered, MODE_3_MIN_DOTS)
+            }
+            3 => {
                 self.set_mode_0();
-                self.mode_0_lcd_interrupt(interrupt);
-                scanline_event = ScanLineEvent::HBlankEntered;
+                let mode_0_dots = SCANLINE_DOTS - MODE_2_DOTS - pixel_transfer_dots;
+                (ScanLineEvent::HBlankEntered, mode_0_dots)
             }
-        }
+            1 if self.scanline == 153 && self.lcd_y_coordinate == 153 => {
+                // LY resets early, so LYC=0 matches during line 153
+                self.lcd_y_coordinate = 0;
+                (ScanLineEvent::None, SCANLINE_DOTS - LINE_153_LY_DOTS)
+            }
+            _ => (
+                self.start_next_scanline(interrupt),
+                self.dots_until_next_mode(),
+            ),
+        };
 
-        if self.scanline_complete() {
-            self.lcd_y_coordinate += 1;
-            self.lcd_y_cycles -= 456;
+        self.update_ly_compare();
+        self.update_stat_interrupt_line(interrupt);
 
-            self.handle_ly_eq_ly_compare(interrupt);
+        (scanline_event, dots_until_next_mode)
+    }
 
-            if self.lcd_y_coordinate == 144 {
-                self.lcd_status |= 0x01; // Set the VBlank flag
-                interrupt.set_vblank_interrupt();
-                if self.lcd_status & 0x10 != 0 {
-                    interrupt.set_lcd_interrupt();
-                }
-                scanline_event = ScanLineEvent::VBlankEntered;
-            } else if self.lcd_y_coordinate > 153 {
-                self.lcd_y_coordinate = 0;
-                self.lcd_status &= 0xFE; // Clear the VBlank flag
+    fn start_next_scanline(&mut self, interrupt: &mut Interrupt) -> ScanLineEvent {
+        self.scanline = if self.scanline >= 153 {
+            0
+        } else {
+            self.scanline + 1
+        };
+        self.lcd_y_coordinate = self.scanline;
+
+        if self.scanline == 144 {
+            self.set_mode_1();
+            interrupt.set_vblank_interrupt();
+
+            // The mode 2 source is also checked when VBlank starts
+            self.update_ly_compare();
+            let is_line_high = self.is_stat_interrupt_source_active(true);
+            self.set_stat_interrupt_line(is_line_high, interrupt);
+
+            ScanLineEvent::VBlankEntered
+        } else if self.scanline < 144 {
+            if self.scanline == 0 {
                 interrupt.disable_vblank_interrupt();
-
-                self.set_mode_2();
-                self.mode_2_lcd_interrupt(interrupt);
-                scanline_event = ScanLineEvent::OAMScanEntered;
-            } else if self.lcd_y_coordinate < 144 {
-                self.set_mode_2();
-                self.mode_2_lcd_interrupt(interrupt);
-                scanline_event = ScanLineEvent::OAMScanEntered;
             }
+
+            self.set_mode_2();
+            ScanLineEvent::OAMScanEntered
+        } else {
+            ScanLineEvent::None
         }
+    }
 
-        scanline_event
+    fn dots_until_next_mode(&self) -> u64 {
+        match self.get_mode() {
+            2 => MODE_2_DOTS,
+            _ if self.scanline == 153 => LINE_153_LY_DOTS,
+            _ => SCANLINE_DOTS,
+        }
     }
 
     pub fn get_palette_data(&self) -> PaletteData {
@@ -304,4 +457,63 @@ impl LCD {
     pub fn get_object_palette_1_data(&self) -> PaletteData {
         PaletteData::from_u8(self.obj_palette_1)
     }
+
+    pub fn get_bg_color(&self, palette: u8, color: u8) -> u16 {
+        self.bg_color_palettes.get_color(palette, color)
+    }
+
+    pub fn get_object_color(&self, palette: u8, color: u8) -> u16 {
+        self.obj_color_palettes.get_color(palette, color)
+    }
+
+    // The CGB boot ROM loads these for DMG games, which can't write the color palettes themselves
+    pub fn load_compatibility_palettes(&mut self, palettes: CompatibilityPalettes) {
+        self.dmg_compatibility = true;
+        self.bg_color_palettes.set_palette(0, palettes.bg);
+        self.obj_color_palettes.set_palette(0, palettes.obj_0);
+        self.obj_color_palettes.set_palette(1, palettes.obj_1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LCD;
+    use crate::io::interrupts::Interrupt;
+
+    // Writes 0 to STAT in the given mode, without an LY coincidence, and returns whether the STAT
+    // interrupt was requested
+    fn write_stat_in_mode(mode: u8, cgb_mode: bool) -> bool {
+        let mut lcd = LCD::default();
+        let mut interrupt = Interrupt::default();
+        lcd.cgb_mode = cgb_mode;
+        lcd.lcd_status = 0x80 | mode;
+
+        lcd.write_u8(0xFF41, 0x00, &mut interrupt);
+        interrupt.read_u8(0xFF0F) & 0x02 != 0
+    }
+
+    #[test]
+    fn dmg_stat_write_bug_fires_outside_mode_2_and_3() {
+        assert!(write_stat_in_mode(0, false));
+        assert!(write_stat_in_mode(1, false));
+        assert!(!write_stat_in_mode(2, false));
+        assert!(!write_stat_in_mode(3, false));
+    }
+
+    #[test]
+    fn dmg_stat_write_bug_fires_on_ly_coincidence() {
+        let mut lcd = LCD::default();
+        let mut interrupt = Interrupt::default();
+        lcd.lcd_status = 0x80 | 0x04 | 0x02;
+
+        lcd.write_u8(0xFF41, 0x00, &mut interrupt);
+        assert_ne!(interrupt.read_u8(0xFF0F) & 0x02, 0);
+    }
+
+    #[test]
+    fn cgb_stat_write_has_no_bug() {
+        for mode in 0..4 {
+            assert!(!write_stat_in_mode(mode, true));
+        }
+    }
 }
@@ -1,10 +1,15 @@
+mod blip_buffer;
 mod cgb_registers;
+pub mod compatibility_palettes;
 mod interrupts;
 pub mod io;
 mod joypad;
 pub mod lcd;
 pub mod oam;
-mod serial;
-mod sound;
+pub mod serial;
+pub mod sgb;
+pub mod sound;
+mod sound_channels;
+pub mod sound_mixer;
 mod timer;
 pub mod vram;
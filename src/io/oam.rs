@@ -9,14 +9,24 @@ pub struct ObjectAttribute {
 }
 
 impl ObjectAttribute {
+    // Y is offset by 16, objects with Y >= 160 are never on screen
     pub fn is_in_scanline(&self, scanline: u8, object_height: u8) -> bool {
-        (scanline + 16) >= self.y && (scanline + 16) < (self.y + object_height)
+        let object_line = scanline as u16 + 16;
+        object_line >= self.y as u16 && object_line < self.y as u16 + object_height as u16
     }
 
     pub fn is_obj_palette_0(&self) -> bool {
         (self.flags & 0x10) == 0
     }
 
+    pub fn get_cgb_palette(&self) -> u8 {
+        self.flags & 0x07
+    }
+
+    pub fn get_vram_bank(&self) -> u8 {
+        (self.flags & 0x08) >> 3
+    }
+
     pub fn is_h_flip(&self) -> bool {
         (self.flags & 0x20) != 0
     }
@@ -33,7 +43,11 @@ impl ObjectAttribute {
 pub struct ObjectAttributeMemory {
     pub oam: [u8; 0xA0],
     pub dma: u8,
-    pub dma_transfer: bool,
+    pub dma_transfer: bool, // Set while a transfer is running, the CPU can't access OAM
+    pub dma_index: u8,
+    pub dma_source: u16,
+    pub dma_value: u8, // Last byte transferred, conflicting CPU reads see it on the bus
+    pub dma_start_time: Option<u64>, // Two M-cycles after the write, the first is spent setting up
 }
 
 pub type ObjectAttributeArray = [ObjectAttribute; 40];
@@ -44,6 +58,10 @@ impl ObjectAttributeMemory {
             oam: [0; 0xA0],
             dma: 0,
             dma_transfer: false,
+            dma_index: 0,
+            dma_source: 0,
+            dma_value: 0xFF,
+            dma_start_time: None,
         }
     }
 
@@ -58,14 +76,24 @@ impl ObjectAttributeMemory {
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match address {
             0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize] = value,
-            0xFF46 => {
-                self.dma = value;
-                self.dma_transfer = true;
-            }
+            0xFF46 => self.dma = value, // The transfer is started by the bus after a delay
             _ => panic!("Invalid OAM Write address: 0x{:04X}", address),
         }
     }
 
+    // Restarting replaces a running transfer, which keeps going until the new one starts
+    pub fn start_dma_transfer(&mut self) {
+        self.dma_transfer = true;
+        self.dma_index = 0;
+        self.dma_start_time = None;
+
+        // Sources from 0xE000 up read work RAM, the same way echo RAM does
+        self.dma_source = match self.dma {
+            0xE0..=0xFF => (self.dma as u16 - 0x20) << 8,
+            _ => (self.dma as u16) << 8,
+        };
+    }
+
     pub fn get_object_attribute(&self, object_index: u8) -> ObjectAttribute {
         let offset = object_index as usize * 4;
         ObjectAttribute {
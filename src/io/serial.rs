@@ -1,6 +1,12 @@
+use super::interrupts::Interrupt;
+
+// Internal clock runs at 8192 Hz
+pub const SERIAL_BIT_CYCLES: u64 = 512;
+
 pub struct Serial {
     transfer_data: u8,    // FF01
     transfer_control: u8, // FF02
+    bits_remaining: u8,
 }
 
 impl Serial {
@@ -8,6 +14,7 @@ impl Serial {
         Serial {
             transfer_data: 0,
             transfer_control: 0,
+            bits_remaining: 0,
         }
     }
 
@@ -24,6 +31,7 @@ impl Serial {
             0xFF01 => self.transfer_data = value,
             0xFF02 => {
                 self.transfer_control = value;
+                self.bits_remaining = 8;
 
                 // Temp Blargg output
                 // if value == 0x81 {
@@ -33,4 +41,25 @@ impl Serial {
             _ => panic!("Invalid Serial Write address: 0x{:04X}", address),
         }
     }
+
+    // Only transfers driven by the internal clock ever progress, there is no link partner to
+    // provide an external clock
+    pub fn is_transferring(&self) -> bool {
+        self.transfer_control & 0x81 == 0x81
+    }
+
+    // Shifts out one bit, with nothing connected 1s are shifted in
+    pub fn shift_bit(&mut self, interrupt: &mut Interrupt) {
+        if !self.is_transferring() {
+            return;
+        }
+
+        self.transfer_data = (self.transfer_data << 1) | 0x01;
+        self.bits_remaining = self.bits_remaining.saturating_sub(1);
+
+        if self.bits_remaining == 0 {
+            self.transfer_control &= 0x7F;
+            interrupt.set_serial_interrupt();
+        }
+    }
 }
@@ -0,0 +1,512 @@
+use crate::ppu::ShadeBuffer;
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+pub type SgbFrameBuffer = [[u16; SGB_SCREEN_WIDTH]; SGB_SCREEN_HEIGHT]; // RGB555 colors
+
+// The Game Boy screen sits in the middle of the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_BITS: u8 = 128;
+const TRANSFER_BYTES: usize = 0x1000;
+const ATTRIBUTE_FILE_BYTES: usize = 90; // 20 x 18 palette numbers of 2 bits
+const ATTRIBUTE_FILE_COUNT: usize = 45;
+
+// Palette 0 until the game sends its own, the boot ROM's "1-A" palette
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    Pal01 = 0x00,
+    Pal23 = 0x01,
+    Pal03 = 0x02,
+    Pal12 = 0x03,
+    AttrBlk = 0x04,
+    AttrLin = 0x05,
+    AttrDiv = 0x06,
+    AttrChr = 0x07,
+    PalSet = 0x0A,
+    PalTrn = 0x0B,
+    MltReq = 0x11,
+    ChrTrn = 0x13,
+    PctTrn = 0x14,
+    AttrTrn = 0x15,
+    AttrSet = 0x16,
+    MaskEn = 0x17,
+    Unsupported, // Sound, SNES code and the other commands that don't affect the Game Boy
+}
+
+impl From<u8> for Command {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => Command::Pal01,
+            0x01 => Command::Pal23,
+            0x02 => Command::Pal03,
+            0x03 => Command::Pal12,
+            0x04 => Command::AttrBlk,
+            0x05 => Command::AttrLin,
+            0x06 => Command::AttrDiv,
+            0x07 => Command::AttrChr,
+            0x0A => Command::PalSet,
+            0x0B => Command::PalTrn,
+            0x11 => Command::MltReq,
+            0x13 => Command::ChrTrn,
+            0x14 => Command::PctTrn,
+            0x15 => Command::AttrTrn,
+            0x16 => Command::AttrSet,
+            0x17 => Command::MaskEn,
+            _ => Command::Unsupported,
+        }
+    }
+}
+
+// VRAM transfers copy 4KB from the next frame the Game Boy displays
+#[derive(Copy, Clone)]
+enum Transfer {
+    Palettes,
+    BorderTiles(usize), // Half of the 256 tiles
+    Border,
+    AttributeFiles,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ScreenMask {
+    None,
+    Freeze, // Keeps showing the last frame
+    Black,
+    Color0,
+}
+
+pub struct SuperGameBoy {
+    pub is_enabled: bool,
+    accepts_packets: bool, // Only games with SGB support in their header can send packets
+
+    // Packets are sent 1 bit at a time by pulsing P14 (0) or P15 (1), after a reset pulse on both
+    joypad_lines: u8,
+    is_receiving: bool,
+    packet: [u8; 16],
+    packet_bit: u8,
+    command: Vec<u8>, // A command is 1 to 7 packets, the first byte has the length
+
+    // MLT_REQ
+    player_count: u8,
+    current_player: u8,
+
+    palettes: [[u16; 4]; 4],    // Color 0 of palette 0 is used by all of them
+    system_palettes: Vec<u16>,  // 512 palettes from PAL_TRN
+    attributes: [[u8; 20]; 18], // Palette of each 8x8 cell of the screen
+    attribute_files: Vec<u8>,   // 45 attribute maps from ATTR_TRN
+    mask: ScreenMask,
+    border_tiles: Vec<u8>, // 256 tiles of 4 bits per pixel from CHR_TRN
+    border_map: Vec<u8>,   // 32 x 28 tile map and 4 palettes of 16 colors from PCT_TRN
+    pending_transfer: Option<Transfer>,
+
+    screen: [[u16; 160]; 144],
+    pub frame_buffer: SgbFrameBuffer,
+}
+
+impl SuperGameBoy {
+    pub fn default() -> SuperGameBoy {
+        SuperGameBoy {
+            is_enabled: false,
+            accepts_packets: false,
+            joypad_lines: 0x30,
+            is_receiving: false,
+            packet: [0; 16],
+            packet_bit: 0,
+            command: Vec::with_capacity(16 * 7),
+            player_count: 1,
+            current_player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; TRANSFER_BYTES / 2],
+            attributes: [[0; 20]; 18],
+            attribute_files: vec![0; ATTRIBUTE_FILE_BYTES * ATTRIBUTE_FILE_COUNT],
+            mask: ScreenMask::None,
+            border_tiles: vec![0; TRANSFER_BYTES * 2],
+            border_map: vec![0; TRANSFER_BYTES],
+            pending_transfer: None,
+            screen: [[0; 160]; 144],
+            frame_buffer: [[0; SGB_SCREEN_WIDTH]; SGB_SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn enable(&mut self, accepts_packets: bool) {
+        self.is_enabled = true;
+        self.accepts_packets = accepts_packets;
+    }
+
+    // Receives the P14 and P15 lines written to the joypad register
+    pub fn write_joypad_lines(&mut self, lines: u8) {
+        if !self.is_enabled {
+            return;
+        }
+
+        let previous_lines = self.joypad_lines;
+        self.joypad_lines = lines;
+
+        match lines {
+            0x00 => {
+                self.is_receiving = true;
+                self.packet = [0; 16];
+                self.packet_bit = 0;
+            }
+            // A bit is only sent once the lines have gone back high
+            0x10 | 0x20 if previous_lines == 0x30 && self.is_receiving => {
+                self.receive_bit(lines == 0x10);
+            }
+            // Releasing the stop bit ends the packet
+            0x30 if self.is_receiving && self.packet_bit > PACKET_BITS => {
+                self.is_receiving = false;
+            }
+            // Deselecting both button groups moves on to the next joypad
+            0x30 if previous_lines != 0x30 && !self.is_receiving && self.player_count > 1 => {
+                self.current_player = (self.current_player + 1) % self.player_count;
+            }
+            _ => {}
+        }
+    }
+
+    // The joypad whose buttons are read, while more than one is connected
+    pub fn get_joypad_id(&self) -> Option<u8> {
+        if self.is_enabled && self.player_count > 1 {
+            Some(self.current_player)
+        } else {
+            None
+        }
+    }
+
+    // Packets are 128 bits sent least significant bit first, followed by a 0 stop bit
+    fn receive_bit(&mut self, bit: bool) {
+        if self.packet_bit < PACKET_BITS && bit {
+            self.packet[(self.packet_bit / 8) as usize] |= 1 << (self.packet_bit % 8);
+        } else if self.packet_bit == PACKET_BITS {
+            self.receive_packet();
+        } else if self.packet_bit > PACKET_BITS {
+            return;
+        }
+
+        self.packet_bit += 1;
+    }
+
+    fn receive_packet(&mut self) {
+        if !self.accepts_packets {
+            return;
+        }
+
+        self.command.extend_from_slice(&self.packet);
+
+        let packet_count = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packet_count * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match Command::from(data[0] >> 3) {
+            Command::Pal01 => self.set_palette_pair(data, 0, 1),
+            Command::Pal23 => self.set_palette_pair(data, 2, 3),
+            Command::Pal03 => self.set_palette_pair(data, 0, 3),
+            Command::Pal12 => self.set_palette_pair(data, 1, 2),
+            Command::AttrBlk => self.set_attribute_blocks(data),
+            Command::AttrLin => self.set_attribute_lines(data),
+            Command::AttrDiv => self.set_attribute_division(data),
+            Command::AttrChr => self.set_attribute_cells(data),
+            Command::PalSet => {
+                for palette in 0..4 {
+                    let index = (read_u16(data, 1 + palette * 2) & 0x1FF) as usize * 4;
+                    self.palettes[palette]
+                        .copy_from_slice(&self.system_palettes[index..(index + 4)]);
+                }
+
+                if data[9] & 0x80 != 0 {
+                    self.apply_attribute_file(data[9] & 0x3F);
+                }
+                if data[9] & 0x40 != 0 {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            Command::AttrSet => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            Command::MltReq => {
+                self.player_count = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            Command::MaskEn => {
+                self.mask = match data[1] & 0x03 {
+                    0x01 => ScreenMask::Freeze,
+                    0x02 => ScreenMask::Black,
+                    0x03 => ScreenMask::Color0,
+                    _ => ScreenMask::None,
+                }
+            }
+            Command::PalTrn => self.pending_transfer = Some(Transfer::Palettes),
+            Command::ChrTrn => {
+                self.pending_transfer = Some(Transfer::BorderTiles((data[1] & 0x01) as usize))
+            }
+            Command::PctTrn => self.pending_transfer = Some(Transfer::Border),
+            Command::AttrTrn => self.pending_transfer = Some(Transfer::AttributeFiles),
+            Command::Unsupported => {}
+        }
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12 set color 0 and colors 1-3 of two palettes
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+        self.palettes[0][0] = read_u16(data, 1);
+
+        for color in 1..4 {
+            self.palettes[first][color] = read_u16(data, 1 + color * 2);
+            self.palettes[second][color] = read_u16(data, 7 + color * 2);
+        }
+    }
+
+    // Each block sets the palette inside a rectangle of cells, on its border and outside of it
+    fn set_attribute_blocks(&mut self, data: &[u8]) {
+        let block_count = (data[1] & 0x1F) as usize;
+
+        for block in data[2..].chunks_exact(6).take(block_count) {
+            let control = block[0] & 0x07;
+            let inside_palette = block[1] & 0x03;
+            let outside_palette = (block[1] >> 4) & 0x03;
+            // Changing only the inside or the outside also changes the border
+            let border_palette = match control {
+                0x01 => inside_palette,
+                0x04 => outside_palette,
+                _ => (block[1] >> 2) & 0x03,
+            };
+            let is_border_changed = control & 0x02 != 0 || control == 0x01 || control == 0x04;
+            let (x1, y1, x2, y2) = (
+                block[2] as usize,
+                block[3] as usize,
+                block[4] as usize,
+                block[5] as usize,
+            );
+
+            for (y, row) in self.attributes.iter_mut().enumerate() {
+                for (x, cell) in row.iter_mut().enumerate() {
+                    let is_inside = x > x1 && x < x2 && y > y1 && y < y2;
+                    let is_border = !is_inside && x >= x1 && x <= x2 && y >= y1 && y <= y2;
+
+                    if is_inside {
+                        if control & 0x01 != 0 {
+                            *cell = inside_palette;
+                        }
+                    } else if is_border {
+                        if is_border_changed {
+                            *cell = border_palette;
+                        }
+                    } else if control & 0x04 != 0 {
+                        *cell = outside_palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // Each entry sets the palette of a whole row or column of cells
+    fn set_attribute_lines(&mut self, data: &[u8]) {
+        let line_count = data[1] as usize;
+
+        for entry in data[2..].iter().take(line_count) {
+            let line = (entry & 0x1F) as usize;
+            let palette = (entry >> 5) & 0x03;
+
+            if entry & 0x80 != 0 {
+                if let Some(row) = self.attributes.get_mut(line) {
+                    *row = [palette; 20];
+                }
+            } else if line < 20 {
+                for row in self.attributes.iter_mut() {
+                    row[line] = palette;
+                }
+            }
+        }
+    }
+
+    // Splits the screen in two at a row or column of cells, the line itself gets a third palette
+    fn set_attribute_division(&mut self, data: &[u8]) {
+        let after_palette = data[1] & 0x03;
+        let before_palette = (data[1] >> 2) & 0x03;
+        let line_palette = (data[1] >> 4) & 0x03;
+        let is_horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+
+        for (y, row) in self.attributes.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let position = if is_horizontal { y } else { x };
+
+                *cell = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before_palette,
+                    std::cmp::Ordering::Equal => line_palette,
+                    std::cmp::Ordering::Greater => after_palette,
+                };
+            }
+        }
+    }
+
+    // Sets the palettes of consecutive cells, 2 bits each, left to right or top to bottom
+    fn set_attribute_cells(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let cell_count = (read_u16(data, 3) as usize).min(360);
+        let is_vertical = data[5] != 0;
+
+        for index in 0..cell_count {
+            let byte = match data.get(6 + index / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            if x >= 20 || y >= 18 {
+                break;
+            }
+
+            self.attributes[y][x] = (byte >> (6 - (index % 4) * 2)) & 0x03;
+
+            if is_vertical {
+                y += 1;
+                if y == 18 {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == 20 {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILE_COUNT {
+            return;
+        }
+
+        let start = file * ATTRIBUTE_FILE_BYTES;
+        for (index, byte) in self.attribute_files[start..(start + ATTRIBUTE_FILE_BYTES)]
+            .iter()
+            .enumerate()
+        {
+            for cell in 0..4 {
+                let position = index * 4 + cell;
+                self.attributes[position / 20][position % 20] = (byte >> (6 - cell * 2)) & 0x03;
+            }
+        }
+    }
+
+    // Called once the Game Boy has finished a frame, runs a pending VRAM transfer from it and
+    // draws the colored screen inside the border
+    pub fn update_frame(&mut self, shades: &ShadeBuffer) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            self.run_transfer(transfer, shades);
+        }
+
+        match self.mask {
+            ScreenMask::None => {
+                for (y, row) in self.screen.iter_mut().enumerate() {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        let shade = shades[y][x] as usize;
+                        *pixel = match shade {
+                            0 => self.palettes[0][0],
+                            _ => self.palettes[self.attributes[y / 8][x / 8] as usize][shade],
+                        };
+                    }
+                }
+            }
+            ScreenMask::Freeze => {}
+            ScreenMask::Black => self.screen = [[0x0000; 160]; 144],
+            ScreenMask::Color0 => self.screen = [[self.palettes[0][0]; 160]; 144],
+        }
+
+        self.draw_border();
+
+        for (y, row) in self.screen.iter().enumerate() {
+            self.frame_buffer[SCREEN_Y + y][SCREEN_X..(SCREEN_X + 160)].copy_from_slice(row);
+        }
+    }
+
+    // The screen shows 4KB as 256 tiles, 20 per row, with the shades as the 2 bits of each pixel
+    fn run_transfer(&mut self, transfer: Transfer, shades: &ShadeBuffer) {
+        let mut data = vec![0; TRANSFER_BYTES];
+
+        for (tile, tile_data) in data.chunks_exact_mut(16).enumerate() {
+            let (tile_x, tile_y) = ((tile % 20) * 8, (tile / 20) * 8);
+
+            for row in 0..8 {
+                for column in 0..8 {
+                    let shade = shades[tile_y + row][tile_x + column];
+                    let bit = 7 - column;
+                    tile_data[row * 2] |= (shade & 0x01) << bit;
+                    tile_data[row * 2 + 1] |= ((shade >> 1) & 0x01) << bit;
+                }
+            }
+        }
+
+        match transfer {
+            Transfer::Palettes => {
+                for (index, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = read_u16(&data, index * 2);
+                }
+            }
+            Transfer::BorderTiles(half) => {
+                let start = half * TRANSFER_BYTES;
+                self.border_tiles[start..(start + TRANSFER_BYTES)].copy_from_slice(&data);
+            }
+            Transfer::Border => self.border_map.copy_from_slice(&data),
+            Transfer::AttributeFiles => {
+                let length = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..length]);
+            }
+        }
+    }
+
+    // Border tiles are SNES tiles of 4 bit planes, color 0 shows the backdrop (SGB color 0)
+    fn draw_border(&mut self) {
+        for tile_y in 0..(SGB_SCREEN_HEIGHT / 8) {
+            for tile_x in 0..(SGB_SCREEN_WIDTH / 8) {
+                let entry = read_u16(&self.border_map, (tile_y * 32 + tile_x) * 2);
+                let tile = (entry & 0xFF) as usize * 32;
+                let palette = ((entry >> 10) & 0x03) as usize; // Border palettes are 4-7
+                let is_x_flipped = entry & 0x4000 != 0;
+                let is_y_flipped = entry & 0x8000 != 0;
+
+                for row in 0..8 {
+                    let tile_row = if is_y_flipped { 7 - row } else { row };
+                    let planes = [
+                        self.border_tiles[tile + tile_row * 2],
+                        self.border_tiles[tile + tile_row * 2 + 1],
+                        self.border_tiles[tile + 16 + tile_row * 2],
+                        self.border_tiles[tile + 16 + tile_row * 2 + 1],
+                    ];
+
+                    for column in 0..8 {
+                        let bit = if is_x_flipped { column } else { 7 - column };
+                        let color = planes.iter().enumerate().fold(0, |color, (plane, byte)| {
+                            color | (((byte >> bit) & 0x01) as usize) << plane
+                        });
+
+                        self.frame_buffer[tile_y * 8 + row][tile_x * 8 + column] = match color {
+                            0 => self.palettes[0][0],
+                            _ => read_u16(&self.border_map, 0x800 + (palette * 16 + color) * 2),
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    (data[offset + 1] as u16) << 8 | data[offset] as u16
+}
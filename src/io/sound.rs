@@ -1,39 +1,256 @@
+use super::{
+    sound_channels::{NoiseChannel, PulseChannel, WaveChannel},
+    sound_mixer::Mixer,
+};
+
+// Rate of the stereo samples produced by the core, the frontend resamples to the host's rate
+pub const SAMPLE_RATE: u64 = 48_000;
+pub const CPU_CLOCK_HZ: u64 = 4_194_304;
+// Samples nobody collects are dropped after a second
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+
+pub type StereoSample = [f32; 2];
+
 pub struct Sound {
+    pulse_1: PulseChannel,
+    pulse_2: PulseChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    registers: [u8; 0x16], // NR10 to NR51 as written, for reading back
     channel_control: u8,
     output_terminal: u8,
-    sound_on: u8,
+    is_powered: bool,
+    frame_sequencer_step: u8, // Next step to run
+    time: u64,
+    pub mixer: Mixer,
+    samples: Vec<StereoSample>,
+    channel_samples: [Vec<f32>; 4], // Each channel on its own, before panning and volume
+    pub cgb_mode: bool,
 }
 
 impl Sound {
     pub fn default() -> Sound {
         Sound {
+            pulse_1: PulseChannel::default(),
+            pulse_2: PulseChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            registers: [0; 0x16],
             channel_control: 0,
             output_terminal: 0,
-            sound_on: 0xF1, // TODO: Alternate FF26 Initial Values [$FF26] = $F1-GB, $F0-SGB ; NR52
+            is_powered: true,
+            frame_sequencer_step: 0,
+            time: 0,
+            mixer: Mixer::default(),
+            samples: Vec::new(),
+            channel_samples: Default::default(),
+            cgb_mode: false,
         }
     }
 
     pub fn write_u8(&mut self, address: u16, value: u8) {
+        if !self.is_powered && !matches!(address, 0xFF26 | 0xFF30..=0xFF3F) {
+            self.write_powered_off(address, value);
+            return;
+        }
+
+        // Enabling length while the next frame sequencer step doesn't clock it clocks it once
+        let is_extra_length_clock = self.frame_sequencer_step & 0x01 == 1;
+
+        if let 0xFF10..=0xFF25 = address {
+            self.registers[(address - 0xFF10) as usize] = value;
+        }
+
         match address {
+            0xFF10 => self.pulse_1.write_sweep(value),
+            0xFF11 => self.pulse_1.write_length_duty(value),
+            0xFF12 => self.pulse_1.write_envelope(value),
+            0xFF13 => self.pulse_1.write_frequency_low(value),
+            0xFF14 => self.pulse_1.write_control(value, is_extra_length_clock),
+            0xFF16 => self.pulse_2.write_length_duty(value),
+            0xFF17 => self.pulse_2.write_envelope(value),
+            0xFF18 => self.pulse_2.write_frequency_low(value),
+            0xFF19 => self.pulse_2.write_control(value, is_extra_length_clock),
+            0xFF1A => self.wave.write_dac(value),
+            0xFF1B => self.wave.write_length(value),
+            0xFF1C => self.wave.write_volume(value),
+            0xFF1D => self.wave.write_frequency_low(value),
+            0xFF1E => self
+                .wave
+                .write_control(value, is_extra_length_clock, self.cgb_mode),
+            0xFF20 => self.noise.write_length(value),
+            0xFF21 => self.noise.write_envelope(value),
+            0xFF22 => self.noise.write_polynomial(value),
+            0xFF23 => self.noise.write_control(value, is_extra_length_clock),
             0xFF24 => self.channel_control = value,
             0xFF25 => self.output_terminal = value,
             0xFF26 => {
-                if value & 0x80 == 0 {
-                    self.sound_on |= 0x80;
-                } else {
-                    self.sound_on &= 0x7F;
+                let is_powered = value & 0x80 != 0;
+
+                if self.is_powered && !is_powered {
+                    self.power_off();
+                } else if !self.is_powered && is_powered {
+                    // The frame sequencer starts over, the next step clocks length
+                    self.frame_sequencer_step = 0;
                 }
+
+                self.is_powered = is_powered;
+            }
+            0xFF30..=0xFF3F => {
+                let index = (address - 0xFF30) as usize;
+                self.wave.write_wave_ram(index, value, self.cgb_mode);
             }
-            _ => (), // Currently just a noop because implementing sound later
+            _ => (),
         }
+
+        self.update_levels();
+    }
+
+    // Only wave RAM, and length on DMG, can be written while powered off
+    fn write_powered_off(&mut self, address: u16, value: u8) {
+        if self.cgb_mode {
+            return;
+        }
+
+        match address {
+            0xFF11 => self.pulse_1.write_length(value),
+            0xFF16 => self.pulse_2.write_length(value),
+            0xFF1B => self.wave.write_length(value),
+            0xFF20 => self.noise.write_length(value),
+            _ => (),
+        }
+    }
+
+    // Clears every register, DMG keeps its length counters running
+    fn power_off(&mut self) {
+        let is_length_kept = !self.cgb_mode;
+
+        self.pulse_1.power_off(is_length_kept);
+        self.pulse_2.power_off(is_length_kept);
+        self.wave.power_off(is_length_kept);
+        self.noise.power_off(is_length_kept);
+        self.registers = [0; 0x16];
+        self.channel_control = 0;
+        self.output_terminal = 0;
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
         match address {
             0xFF24 => self.channel_control,
             0xFF25 => self.output_terminal,
-            0xFF26 => self.sound_on,
+            0xFF26 => ((self.is_powered as u8) << 7) | 0x70 | self.get_channel_status(),
+            0xFF10..=0xFF23 => self.registers[(address - 0xFF10) as usize],
+            0xFF30..=0xFF3F => {
+                let index = (address - 0xFF30) as usize;
+                self.wave.read_wave_ram(index, self.cgb_mode)
+            }
             _ => 0xFF,
         }
     }
+
+    // Lower 4 bits of NR52, set while each channel is on
+    fn get_channel_status(&self) -> u8 {
+        (self.pulse_1.is_enabled as u8)
+            | ((self.pulse_2.is_enabled as u8) << 1)
+            | ((self.wave.is_enabled as u8) << 2)
+            | ((self.noise.is_enabled as u8) << 3)
+    }
+
+    // Clocked at 512 Hz from DIV. Length runs on even steps, sweep on steps 2 and 6 and the
+    // envelopes on step 7. It stands still while the APU is powered off
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.is_powered {
+            return;
+        }
+
+        let step = self.frame_sequencer_step;
+
+        if step & 0x01 == 0 {
+            self.pulse_1.clock_length();
+            self.pulse_2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if step == 2 || step == 6 {
+            self.pulse_1.clock_sweep();
+        }
+
+        if step == 7 {
+            self.pulse_1.clock_envelope();
+            self.pulse_2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+
+        self.frame_sequencer_step = (step + 1) & 0x07;
+        self.update_levels();
+    }
+
+    // Runs the channels up to the given time, reading out the samples before it
+    pub fn sync(&mut self, time: u64) {
+        if time <= self.time {
+            return;
+        }
+
+        let (start, panning, volume) = (self.time, self.output_terminal, self.channel_control);
+        self.mixer
+            .sync_channel(0, &mut self.pulse_1, start, time, panning, volume);
+        self.mixer
+            .sync_channel(1, &mut self.pulse_2, start, time, panning, volume);
+        self.mixer
+            .sync_channel(2, &mut self.wave, start, time, panning, volume);
+        self.mixer
+            .sync_channel(3, &mut self.noise, start, time, panning, volume);
+        self.time = time;
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.drain(..MAX_BUFFERED_SAMPLES / 2);
+        }
+        for samples in &mut self.channel_samples {
+            if samples.len() >= MAX_BUFFERED_SAMPLES {
+                samples.drain(..MAX_BUFFERED_SAMPLES / 2);
+            }
+        }
+        self.mixer.read_samples(
+            time,
+            self.cgb_mode,
+            &mut self.samples,
+            &mut self.channel_samples,
+        );
+    }
+
+    pub fn set_muted_channels(&mut self, muted_channels: [bool; 4]) {
+        self.mixer.muted_channels = muted_channels;
+        self.update_levels();
+    }
+
+    // NR10 to NR51 as last written, then NR52
+    pub fn get_registers(&self) -> [u8; 0x17] {
+        let mut registers = [0; 0x17];
+        registers[..0x16].copy_from_slice(&self.registers);
+        registers[0x16] = self.read_u8(0xFF26);
+        registers
+    }
+
+    // Register writes and the frame sequencer can change any channel's output, or the mixing
+    fn update_levels(&mut self) {
+        let (time, panning, volume) = (self.time, self.output_terminal, self.channel_control);
+        self.mixer
+            .update_level(0, &self.pulse_1, time, panning, volume);
+        self.mixer
+            .update_level(1, &self.pulse_2, time, panning, volume);
+        self.mixer
+            .update_level(2, &self.wave, time, panning, volume);
+        self.mixer
+            .update_level(3, &self.noise, time, panning, volume);
+    }
+
+    pub fn take_samples(&mut self) -> Vec<StereoSample> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Pulse 1, pulse 2, wave and noise samples, in step with the mixed samples
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        std::mem::take(&mut self.channel_samples)
+    }
 }
@@ -0,0 +1,648 @@
+// Pulse, wave and noise channels. Frequency timers count T-cycles at the normal speed clock rate,
+// length, envelope and sweep are clocked by the frame sequencer
+
+const DUTY_WAVEFORMS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Steps a channel through time for the mixer, which adds a step to the output whenever the
+// channel's output changes
+pub trait SoundChannel {
+    fn advance(&mut self, cycles: u32);
+    fn get_timer(&self) -> u32; // Dots until the channel's next step
+    fn is_dac_enabled(&self) -> bool;
+    fn get_output(&self) -> u8; // Digital output from 0 to 15
+}
+
+// How long after the wave channel reads wave RAM the CPU can still access it on DMG
+const DMG_WAVE_RAM_ACCESS_DOTS: u32 = 2;
+
+// Turns the channel off once it runs out, if enabled by bit 6 of NRx4
+pub struct LengthCounter {
+    pub is_enabled: bool,
+    counter: u16,
+    max_length: u16,
+}
+
+impl LengthCounter {
+    fn new(max_length: u16) -> LengthCounter {
+        LengthCounter {
+            is_enabled: false,
+            counter: 0,
+            max_length,
+        }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = self.max_length - length;
+    }
+
+    // Returns true when the counter runs out
+    fn clock(&mut self) -> bool {
+        if self.is_enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+
+        false
+    }
+
+    // Enabling the counter while the frame sequencer's next step doesn't clock it clocks it once
+    // more, which can run it out. Returns true in that case
+    fn set_enabled(&mut self, is_enabled: bool, is_extra_clock: bool) -> bool {
+        let was_enabled = self.is_enabled;
+        self.is_enabled = is_enabled;
+
+        !was_enabled && is_extra_clock && self.clock()
+    }
+
+    fn trigger(&mut self, is_extra_clock: bool) {
+        if self.counter == 0 {
+            self.counter = self.max_length;
+
+            if self.is_enabled && is_extra_clock {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+pub struct VolumeEnvelope {
+    initial_volume: u8,
+    is_increasing: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl VolumeEnvelope {
+    fn default() -> VolumeEnvelope {
+        VolumeEnvelope {
+            initial_volume: 0,
+            is_increasing: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.is_increasing = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    // A period of 0 stops the envelope
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.is_increasing && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.is_increasing && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// Only channel 1 has a sweep, channel 2 never sets one up so it stays disabled
+struct Sweep {
+    period: u8,
+    is_negated: bool,
+    shift: u8,
+    timer: u8,
+    is_enabled: bool,
+    shadow_frequency: u16,
+    has_negated: bool, // Clearing negate after a negated calculation turns the channel off
+}
+
+impl Sweep {
+    fn default() -> Sweep {
+        Sweep {
+            period: 0,
+            is_negated: false,
+            shift: 0,
+            timer: 0,
+            is_enabled: false,
+            shadow_frequency: 0,
+            has_negated: false,
+        }
+    }
+
+    // A period of 0 reloads the timer with 8
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Returns None when the new frequency overflows, which turns the channel off
+    fn calculate_frequency(&mut self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift;
+        let frequency = if self.is_negated {
+            self.has_negated = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if frequency > 2047 {
+            None
+        } else {
+            Some(frequency)
+        }
+    }
+}
+
+pub struct PulseChannel {
+    pub is_enabled: bool,
+    is_dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    pub length: LengthCounter,
+    envelope: VolumeEnvelope,
+    sweep: Sweep,
+}
+
+impl PulseChannel {
+    pub fn default() -> PulseChannel {
+        PulseChannel {
+            is_enabled: false,
+            is_dac_enabled: false,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::default(),
+            sweep: Sweep::default(),
+        }
+    }
+
+    pub fn write_sweep(&mut self, value: u8) {
+        self.sweep.period = (value >> 4) & 0x07;
+        self.sweep.is_negated = value & 0x08 != 0;
+        self.sweep.shift = value & 0x07;
+
+        if !self.sweep.is_negated && self.sweep.has_negated {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn write_length_duty(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.write_length(value);
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load((value & 0x3F) as u16);
+    }
+
+    // Powering the APU off resets everything, except the length counter on DMG
+    pub fn power_off(&mut self, is_length_kept: bool) {
+        let length_counter = self.length.counter;
+        *self = PulseChannel::default();
+
+        if is_length_kept {
+            self.length.counter = length_counter;
+        }
+    }
+
+    // The DAC is off when the upper 5 bits are clear, which also turns the channel off
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        self.is_dac_enabled = value & 0xF8 != 0;
+
+        if !self.is_dac_enabled {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x0700) | value as u16;
+    }
+
+    pub fn write_control(&mut self, value: u8, is_extra_length_clock: bool) {
+        self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+
+        if self
+            .length
+            .set_enabled(value & 0x40 != 0, is_extra_length_clock)
+        {
+            self.is_enabled = false;
+        }
+
+        if value & 0x80 != 0 {
+            self.trigger(is_extra_length_clock);
+        }
+    }
+
+    fn trigger(&mut self, is_extra_length_clock: bool) {
+        self.is_enabled = self.is_dac_enabled;
+        self.length.trigger(is_extra_length_clock);
+        self.timer = self.get_period();
+        self.envelope.trigger();
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.has_negated = false;
+        self.sweep.reload_timer();
+        self.sweep.is_enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+
+        // The overflow check runs right away
+        if self.sweep.shift != 0 && self.sweep.calculate_frequency().is_none() {
+            self.is_enabled = false;
+        }
+    }
+
+    fn get_period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+
+        if self.sweep.timer != 0 {
+            return;
+        }
+
+        self.sweep.reload_timer();
+
+        if !self.sweep.is_enabled || self.sweep.period == 0 {
+            return;
+        }
+
+        match self.sweep.calculate_frequency() {
+            Some(frequency) if self.sweep.shift != 0 => {
+                self.sweep.shadow_frequency = frequency;
+                self.frequency = frequency;
+
+                // The new frequency is checked for overflow again, but not written back
+                if self.sweep.calculate_frequency().is_none() {
+                    self.is_enabled = false;
+                }
+            }
+            Some(_) => {}
+            None => self.is_enabled = false,
+        }
+    }
+}
+
+impl SoundChannel for PulseChannel {
+    fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.get_period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn get_timer(&self) -> u32 {
+        self.timer
+    }
+
+    fn is_dac_enabled(&self) -> bool {
+        self.is_dac_enabled
+    }
+
+    fn get_output(&self) -> u8 {
+        if !self.is_enabled {
+            return 0;
+        }
+
+        let is_high = (DUTY_WAVEFORMS[self.duty as usize] >> (7 - self.duty_position)) & 0x01;
+        is_high * self.envelope.volume
+    }
+}
+
+pub struct WaveChannel {
+    pub is_enabled: bool,
+    is_dac_enabled: bool,
+    volume_code: u8, // 0 mutes, then 100%, 50% and 25%
+    frequency: u16,
+    timer: u32,
+    position: u8, // Index of the 4 bit sample in wave RAM
+    sample_buffer: u8,
+    dots_since_read: u32,
+    pub length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    pub fn default() -> WaveChannel {
+        WaveChannel {
+            is_enabled: false,
+            is_dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            dots_since_read: u32::MAX,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+        }
+    }
+
+    pub fn write_dac(&mut self, value: u8) {
+        self.is_dac_enabled = value & 0x80 != 0;
+
+        if !self.is_dac_enabled {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(value as u16);
+    }
+
+    // Wave RAM survives power off
+    pub fn power_off(&mut self, is_length_kept: bool) {
+        let length_counter = self.length.counter;
+        let wave_ram = self.wave_ram;
+        *self = WaveChannel::default();
+        self.wave_ram = wave_ram;
+
+        if is_length_kept {
+            self.length.counter = length_counter;
+        }
+    }
+
+    // While the channel plays, wave RAM accesses go to the byte it is reading instead. On DMG
+    // that only works right as the channel reads it, otherwise reads return 0xFF and writes are
+    // dropped
+    pub fn read_wave_ram(&self, index: usize, cgb_mode: bool) -> u8 {
+        if !self.is_enabled {
+            self.wave_ram[index]
+        } else if cgb_mode || self.dots_since_read < DMG_WAVE_RAM_ACCESS_DOTS {
+            self.wave_ram[(self.position / 2) as usize]
+        } else {
+            0xFF
+        }
+    }
+
+    pub fn write_wave_ram(&mut self, index: usize, value: u8, cgb_mode: bool) {
+        if !self.is_enabled {
+            self.wave_ram[index] = value;
+        } else if cgb_mode || self.dots_since_read < DMG_WAVE_RAM_ACCESS_DOTS {
+            self.wave_ram[(self.position / 2) as usize] = value;
+        }
+    }
+
+    pub fn write_volume(&mut self, value: u8) {
+        self.volume_code = (value >> 5) & 0x03;
+    }
+
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x0700) | value as u16;
+    }
+
+    pub fn write_control(&mut self, value: u8, is_extra_length_clock: bool, cgb_mode: bool) {
+        self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+
+        if self
+            .length
+            .set_enabled(value & 0x40 != 0, is_extra_length_clock)
+        {
+            self.is_enabled = false;
+        }
+
+        if value & 0x80 != 0 {
+            // Retriggering on DMG just as the channel reads wave RAM corrupts its first bytes
+            if !cgb_mode && self.is_enabled && self.timer <= DMG_WAVE_RAM_ACCESS_DOTS {
+                self.corrupt_wave_ram();
+            }
+
+            self.is_enabled = self.is_dac_enabled;
+            self.length.trigger(is_extra_length_clock);
+            // The first sample is read a little later than a full period
+            self.timer = self.get_period() + 6;
+            self.position = 0;
+            self.dots_since_read = u32::MAX;
+        }
+    }
+
+    // The byte about to be read is copied over the first byte, or over the first 4 bytes along
+    // with the rest of its 4 byte block
+    fn corrupt_wave_ram(&mut self) {
+        let index = (((self.position + 1) & 0x1F) / 2) as usize;
+
+        if index < 4 {
+            self.wave_ram[0] = self.wave_ram[index];
+        } else {
+            let block = index & !0x03;
+            self.wave_ram.copy_within(block..(block + 4), 0);
+        }
+    }
+
+    fn get_period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+}
+
+impl SoundChannel for WaveChannel {
+    fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        let mut has_read = false;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.get_period();
+            self.position = (self.position + 1) & 0x1F;
+            has_read = true;
+
+            // High nibble first
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample_buffer = if self.position & 0x01 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+
+        self.timer -= cycles;
+        self.dots_since_read = if has_read {
+            cycles
+        } else {
+            self.dots_since_read.saturating_add(cycles)
+        };
+    }
+
+    fn get_timer(&self) -> u32 {
+        self.timer
+    }
+
+    fn is_dac_enabled(&self) -> bool {
+        self.is_dac_enabled
+    }
+
+    fn get_output(&self) -> u8 {
+        if !self.is_enabled {
+            return 0;
+        }
+
+        match self.volume_code {
+            0 => 0,
+            code => self.sample_buffer >> (code - 1),
+        }
+    }
+}
+
+// Pseudo random noise from a 15 bit linear feedback shift register
+pub struct NoiseChannel {
+    pub is_enabled: bool,
+    is_dac_enabled: bool,
+    clock_shift: u8,
+    is_7_bit: bool, // Also feeds back into bit 6 for a shorter, more tonal sequence
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+    pub length: LengthCounter,
+    envelope: VolumeEnvelope,
+}
+
+impl NoiseChannel {
+    pub fn default() -> NoiseChannel {
+        NoiseChannel {
+            is_enabled: false,
+            is_dac_enabled: false,
+            clock_shift: 0,
+            is_7_bit: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::default(),
+        }
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load((value & 0x3F) as u16);
+    }
+
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        self.is_dac_enabled = value & 0xF8 != 0;
+
+        if !self.is_dac_enabled {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn power_off(&mut self, is_length_kept: bool) {
+        let length_counter = self.length.counter;
+        *self = NoiseChannel::default();
+
+        if is_length_kept {
+            self.length.counter = length_counter;
+        }
+    }
+
+    pub fn write_polynomial(&mut self, value: u8) {
+        self.clock_shift = value >> 4;
+        self.is_7_bit = value & 0x08 != 0;
+        self.divisor_code = value & 0x07;
+    }
+
+    pub fn write_control(&mut self, value: u8, is_extra_length_clock: bool) {
+        if self
+            .length
+            .set_enabled(value & 0x40 != 0, is_extra_length_clock)
+        {
+            self.is_enabled = false;
+        }
+
+        if value & 0x80 != 0 {
+            self.is_enabled = self.is_dac_enabled;
+            self.length.trigger(is_extra_length_clock);
+            self.timer = self.get_period();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+        }
+    }
+
+    fn get_period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+impl SoundChannel for NoiseChannel {
+    fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.get_period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+            if self.is_7_bit {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn get_timer(&self) -> u32 {
+        self.timer
+    }
+
+    fn is_dac_enabled(&self) -> bool {
+        self.is_dac_enabled
+    }
+
+    // Outputs the volume while bit 0 of the LFSR is clear
+    fn get_output(&self) -> u8 {
+        if !self.is_enabled {
+            return 0;
+        }
+
+        ((!self.lfsr & 0x01) as u8) * self.envelope.volume
+    }
+}
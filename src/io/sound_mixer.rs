@@ -0,0 +1,152 @@
+use super::{
+    blip_buffer::BlipBuffer,
+    sound::{StereoSample, CPU_CLOCK_HZ, SAMPLE_RATE},
+    sound_channels::SoundChannel,
+};
+
+// How much charge the DMG and CGB output capacitors keep each T-cycle
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+const CGB_CHARGE_FACTOR: f64 = 0.998943;
+
+// Mixes the channels into band-limited left and right outputs. Each DAC turns the channel's 0 to
+// 15 output into -1.0 to 1.0, NR51 routes it to the left and right outputs, then NR50 scales each
+// side from 1/8 to 8/8. Each channel's DAC output is also kept on its own before panning, muted
+// channels are only left out of the mix
+pub struct Mixer {
+    left: BlipBuffer,
+    right: BlipBuffer,
+    levels: [StereoSample; 4], // Each channel's current contribution to both sides
+    capacitors: StereoSample,
+    channels: [BlipBuffer; 4],
+    channel_levels: [f32; 4],
+    channel_capacitors: [f32; 4],
+    pub muted_channels: [bool; 4],
+    pub is_high_pass_filter_enabled: bool,
+    left_samples: Vec<f32>,
+    right_samples: Vec<f32>,
+}
+
+impl Mixer {
+    pub fn default() -> Mixer {
+        Mixer {
+            left: BlipBuffer::new(CPU_CLOCK_HZ, SAMPLE_RATE),
+            right: BlipBuffer::new(CPU_CLOCK_HZ, SAMPLE_RATE),
+            levels: [[0.; 2]; 4],
+            capacitors: [0.; 2],
+            channels: std::array::from_fn(|_| BlipBuffer::new(CPU_CLOCK_HZ, SAMPLE_RATE)),
+            channel_levels: [0.; 4],
+            channel_capacitors: [0.; 4],
+            muted_channels: [false; 4],
+            is_high_pass_filter_enabled: true,
+            left_samples: Vec::new(),
+            right_samples: Vec::new(),
+        }
+    }
+
+    // Runs a channel from start to end, adding a step at every change in its output
+    pub fn sync_channel(
+        &mut self,
+        index: usize,
+        channel: &mut impl SoundChannel,
+        start: u64,
+        end: u64,
+        panning: u8,
+        volume: u8,
+    ) {
+        let mut time = start;
+
+        while time + channel.get_timer() as u64 <= end {
+            let cycles = channel.get_timer();
+            channel.advance(cycles);
+            time += cycles as u64;
+            self.update_level(index, channel, time, panning, volume);
+        }
+
+        channel.advance((end - time) as u32);
+    }
+
+    // Called whenever a channel's output may have changed
+    pub fn update_level(
+        &mut self,
+        index: usize,
+        channel: &impl SoundChannel,
+        time: u64,
+        panning: u8,
+        volume: u8,
+    ) {
+        let analog = if channel.is_dac_enabled() {
+            channel.get_output() as f32 / 7.5 - 1.
+        } else {
+            0.
+        };
+
+        // NR51 has the left outputs in the upper nibble
+        let is_left = (panning >> (index + 4)) & 0x01 & !self.muted_channels[index] as u8;
+        let is_right = (panning >> index) & 0x01 & !self.muted_channels[index] as u8;
+        let left_volume = (((volume >> 4) & 0x07) + 1) as f32 / 8.;
+        let right_volume = ((volume & 0x07) + 1) as f32 / 8.;
+
+        let level = [
+            analog / 4. * left_volume * is_left as f32,
+            analog / 4. * right_volume * is_right as f32,
+        ];
+
+        let [left_level, right_level] = self.levels[index];
+        self.left.add_delta(time, level[0] - left_level);
+        self.right.add_delta(time, level[1] - right_level);
+        self.levels[index] = level;
+
+        let channel_level = analog / 4.;
+        self.channels[index].add_delta(time, channel_level - self.channel_levels[index]);
+        self.channel_levels[index] = channel_level;
+    }
+
+    // Reads out the samples before the given time, optionally through the high pass filter the
+    // hardware's output capacitors make, which removes the DACs' DC offset
+    pub fn read_samples(
+        &mut self,
+        time: u64,
+        cgb_mode: bool,
+        samples: &mut Vec<StereoSample>,
+        channel_samples: &mut [Vec<f32>; 4],
+    ) {
+        let charge_factor = if cgb_mode {
+            CGB_CHARGE_FACTOR
+        } else {
+            DMG_CHARGE_FACTOR
+        };
+        let charge_factor = charge_factor.powf(CPU_CLOCK_HZ as f64 / SAMPLE_RATE as f64) as f32;
+        let high_pass = |input: f32, capacitor: &mut f32| {
+            let output = input - *capacitor;
+            *capacitor = input - output * charge_factor;
+            output
+        };
+
+        self.left_samples.clear();
+        self.right_samples.clear();
+        self.left.read_samples(time, &mut self.left_samples);
+        self.right.read_samples(time, &mut self.right_samples);
+
+        for (left, right) in self.left_samples.iter().zip(&self.right_samples) {
+            if self.is_high_pass_filter_enabled {
+                samples.push([
+                    high_pass(*left, &mut self.capacitors[0]),
+                    high_pass(*right, &mut self.capacitors[1]),
+                ]);
+            } else {
+                samples.push([*left, *right]);
+            }
+        }
+
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            let start = channel_samples[index].len();
+            channel.read_samples(time, &mut channel_samples[index]);
+
+            if self.is_high_pass_filter_enabled {
+                for sample in &mut channel_samples[index][start..] {
+                    *sample = high_pass(*sample, &mut self.channel_capacitors[index]);
+                }
+            }
+        }
+    }
+}
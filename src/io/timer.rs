@@ -1,37 +1,92 @@
 use super::interrupts::Interrupt;
 
+// The APU frame sequencer is clocked by the falling edge of DIV bit 4, or bit 5 in double speed
+// so it keeps the same rate
+const DIV_APU_PERIOD: u64 = 0x2000;
+
+// TMA is loaded into TIMA one M-cycle after it overflows
+const TIMA_RELOAD_DELAY: u64 = 4;
+
+// Timer is synced lazily, the system counter is only brought up to date when a register is
+// accessed or a timer event is due
+// The system counter runs at the CPU clock, times are in normal speed T-cycles, so the counter
+// advances twice per T-cycle in double speed
 pub struct Timer {
-    m_cycles: u16,
-    div: u8,
+    system_counter: u16, // DIV is the upper 8 bits
+    last_sync: u64,
     tima: u8,
     tma: u8,
     tac: u8,
+    reload_time: Option<u64>, // Set while TIMA reads 0 after overflowing
+    last_reload_time: Option<u64>,
+    pub cgb_mode: bool,
+    pub is_double_speed: bool,
 }
 
 impl Timer {
     pub fn default() -> Timer {
         Timer {
-            m_cycles: 0,
-            div: 0,
+            system_counter: 0xABCC, // DMG value when boot hands over
+            last_sync: 0,
             tima: 0,
             tma: 0,
             tac: 0,
+            reload_time: None,
+            last_reload_time: None,
+            cgb_mode: false,
+            is_double_speed: false,
         }
     }
 
+    // Expects the timer to already be synced to the time of the write
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match address {
-            0xFF04 => self.m_cycles = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
-            0xFF07 => self.tac = value,
+            0xFF04 => {
+                let previous_signal = self.timer_signal();
+                self.system_counter = 0;
+
+                // Resetting the counter can cause a falling edge on the selected bit
+                if previous_signal {
+                    self.increment_tima();
+                }
+            }
+            0xFF05 => {
+                // Writing during the M-cycle TMA is loaded is ignored
+                if self.is_reload_cycle() {
+                    return;
+                }
+
+                // Writing while the reload is pending cancels the reload and the interrupt
+                self.reload_time = None;
+                self.tima = value;
+            }
+            0xFF06 => {
+                self.tma = value;
+
+                // Writing during the M-cycle TMA is loaded also updates TIMA
+                if self.is_reload_cycle() {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                let previous_signal = self.timer_signal();
+                let previous_tac = self.tac;
+                self.tac = value;
+
+                let is_falling_edge = previous_signal && !self.timer_signal();
+                let is_cgb_glitch = self.cgb_mode && self.is_cgb_disable_glitch(previous_tac);
+
+                if is_falling_edge || is_cgb_glitch {
+                    self.increment_tima();
+                }
+            }
             _ => panic!("Invalid Timer Write address: 0x{:04X}", address),
         }
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
         match address {
-            0xFF04 => (self.m_cycles >> 6) as u8,
+            0xFF04 => (self.system_counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
             0xFF07 => self.tac,
@@ -39,36 +94,259 @@ impl Timer {
         }
     }
 
-    pub fn update_timer(&mut self, cycle_buffer: &mut u8, interrupt: &mut Interrupt) {
-        while *cycle_buffer > 0 {
-            let prev_m_cycles = self.m_cycles;
-            self.m_cycles = self.m_cycles.wrapping_add(1);
-
-            let tac_enabled = self.tac & 0x04 != 0;
-            let tac_freq = self.tac & 0x03;
-
-            let tac_mask = match tac_freq {
-                0 => 0x80,
-                1 => 0x02,
-                2 => 0x08,
-                3 => 0x20,
-                _ => panic!("Invalid TAC Frequency: {:02X}", tac_freq),
-            };
-            let prev_masked_cycles = prev_m_cycles & tac_mask;
-            let masked_cycles = self.m_cycles & tac_mask;
-
-            // Falling edge for enabled TAC
-            // TODO: CBG Has tac_enabled after falling edge
-            if tac_enabled && masked_cycles == 0 && prev_masked_cycles > 0 {
-                self.tima = self.tima.wrapping_add(1);
-
-                if self.tima == 0 {
-                    self.tima = self.tma;
-                    interrupt.set_timer_interrupt(); // TODO: Technically happens after another m-cycle, will fix later
-                }
+    fn is_tac_enabled(&self) -> bool {
+        self.tac & 0x04 != 0
+    }
+
+    // Number of T-cycles between TIMA increments, TIMA increments on the falling edge of the
+    // selected system counter bit
+    fn tima_period(&self) -> u64 {
+        Timer::tac_period(self.tac)
+    }
+
+    fn tac_period(tac: u8) -> u64 {
+        match tac & 0x03 {
+            0 => 0x400,
+            1 => 0x10,
+            2 => 0x40,
+            3 => 0x100,
+            _ => unreachable!(),
+        }
+    }
+
+    fn is_counter_bit_set(&self, tac: u8) -> bool {
+        self.system_counter as u64 & (Timer::tac_period(tac) / 2) != 0
+    }
+
+    // Selected counter bit ANDed with the enable bit, TIMA increments on its falling edge
+    fn timer_signal(&self) -> bool {
+        self.is_tac_enabled() && self.is_counter_bit_set(self.tac)
+    }
+
+    // CGB switches the frequency before the enable bit, so disabling the timer while switching
+    // to a frequency whose counter bit is set briefly raises the signal before it falls
+    fn is_cgb_disable_glitch(&self, previous_tac: u8) -> bool {
+        let is_disabling = previous_tac & 0x04 != 0 && !self.is_tac_enabled();
+
+        is_disabling && self.is_counter_bit_set(self.tac)
+    }
+
+    fn is_reload_cycle(&self) -> bool {
+        self.last_reload_time == Some(self.last_sync)
+    }
+
+    fn speed_multiplier(&self) -> u64 {
+        if self.is_double_speed {
+            2
+        } else {
+            1
+        }
+    }
+
+    // Converts a number of system counter ticks from the last sync into a time
+    fn counter_ticks_to_time(&self, counter_ticks: u64) -> u64 {
+        self.last_sync + counter_ticks.div_ceil(self.speed_multiplier())
+    }
+
+    fn increment_tima(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.reload_time = Some(self.counter_ticks_to_time(TIMA_RELOAD_DELAY));
+        } else {
+            self.tima += 1;
+        }
+    }
+
+    // Brings the system counter and TIMA up to the given time
+    pub fn sync(&mut self, now: u64) {
+        if now <= self.last_sync {
+            return;
+        }
+
+        let elapsed = (now - self.last_sync) * self.speed_multiplier();
+        let previous_counter = self.system_counter as u64;
+        self.last_sync = now;
+        self.system_counter = (previous_counter + elapsed) as u16;
+
+        if self.is_tac_enabled() {
+            let period = self.tima_period();
+            let falling_edges = (previous_counter + elapsed) / period - previous_counter / period;
+
+            // Overflows are handled by the timer event, so this never wraps past it
+            self.tima = (self.tima as u64 + falling_edges).min(0xFF) as u8;
+        }
+    }
+
+    // Time of the next overflow or pending reload, relative to the last sync
+    pub fn next_event_time(&self) -> Option<u64> {
+        if self.reload_time.is_some() {
+            return self.reload_time;
+        }
+
+        if !self.is_tac_enabled() {
+            return None;
+        }
+
+        let period = self.tima_period();
+        let cycles_to_first_edge = period - (self.system_counter as u64 % period);
+        let remaining_edges = 0x100 - self.tima as u64;
+
+        Some(self.counter_ticks_to_time(cycles_to_first_edge + (remaining_edges - 1) * period))
+    }
+
+    // TIMA reads 0 for one M-cycle after overflowing, then TMA is loaded and the interrupt is
+    // requested
+    pub fn handle_event(&mut self, time: u64, interrupt: &mut Interrupt) {
+        self.sync(time);
+
+        match self.reload_time {
+            Some(reload_time) if reload_time <= time => {
+                self.reload_time = None;
+                self.last_reload_time = Some(time);
+                self.tima = self.tma;
+                interrupt.set_timer_interrupt();
+            }
+            _ => {
+                self.tima = 0;
+                self.reload_time = Some(self.counter_ticks_to_time(TIMA_RELOAD_DELAY));
             }
+        }
+    }
+
+    // Time of the next DIV bit 4 falling edge, relative to the last sync
+    pub fn next_div_apu_time(&self) -> u64 {
+        let period = DIV_APU_PERIOD * self.speed_multiplier();
+
+        self.counter_ticks_to_time(period - (self.system_counter as u64 % period))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use crate::io::interrupts::Interrupt;
+
+    // Starts from a reset system counter at time 0, TAC 0x05 increments TIMA every 16 T-cycles
+    fn start_timer(tac: u8, tima: u8, tma: u8, cgb_mode: bool) -> (Timer, Interrupt) {
+        let mut timer = Timer::default();
+        timer.cgb_mode = cgb_mode;
+        timer.write_u8(0xFF04, 0);
+        timer.write_u8(0xFF06, tma);
+        timer.write_u8(0xFF05, tima);
+        timer.write_u8(0xFF07, tac);
+
+        (timer, Interrupt::default())
+    }
+
+    // Handles timer events up to the given time the way the bus does, then syncs to it
+    fn run_until(timer: &mut Timer, interrupt: &mut Interrupt, time: u64) {
+        while let Some(event_time) = timer.next_event_time() {
+            if event_time > time {
+                break;
+            }
+            timer.handle_event(event_time, interrupt);
+        }
+
+        timer.sync(time);
+    }
+
+    fn is_timer_interrupt_requested(interrupt: &Interrupt) -> bool {
+        interrupt.read_u8(0xFF0F) & 0x04 != 0
+    }
+
+    #[test]
+    fn reload_is_delayed_by_one_m_cycle() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0xFF, 0x23, false);
+
+        run_until(&mut timer, &mut interrupt, 16);
+        assert_eq!(timer.read_u8(0xFF05), 0x00);
+        assert!(!is_timer_interrupt_requested(&interrupt));
+
+        run_until(&mut timer, &mut interrupt, 19);
+        assert_eq!(timer.read_u8(0xFF05), 0x00);
+        assert!(!is_timer_interrupt_requested(&interrupt));
+
+        run_until(&mut timer, &mut interrupt, 20);
+        assert_eq!(timer.read_u8(0xFF05), 0x23);
+        assert!(is_timer_interrupt_requested(&interrupt));
+    }
+
+    #[test]
+    fn tima_write_during_delay_cancels_reload() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0xFF, 0x23, false);
+
+        run_until(&mut timer, &mut interrupt, 17);
+        timer.write_u8(0xFF05, 0x42);
+        run_until(&mut timer, &mut interrupt, 24);
+
+        assert_eq!(timer.read_u8(0xFF05), 0x42);
+        assert!(!is_timer_interrupt_requested(&interrupt));
+    }
+
+    #[test]
+    fn tima_write_on_reload_cycle_is_ignored() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0xFF, 0x23, false);
+
+        run_until(&mut timer, &mut interrupt, 20);
+        timer.write_u8(0xFF05, 0x42);
+
+        assert_eq!(timer.read_u8(0xFF05), 0x23);
+        assert!(is_timer_interrupt_requested(&interrupt));
+    }
+
+    #[test]
+    fn tma_write_on_reload_cycle_propagates() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0xFF, 0x23, false);
+
+        run_until(&mut timer, &mut interrupt, 20);
+        timer.write_u8(0xFF06, 0x42);
+
+        assert_eq!(timer.read_u8(0xFF05), 0x42);
+        assert_eq!(timer.read_u8(0xFF06), 0x42);
+    }
+
+    #[test]
+    fn div_write_causes_falling_edge() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0x10, 0x00, false);
+
+        // Counter bit 3 is still clear, resetting the counter doesn't increment TIMA
+        run_until(&mut timer, &mut interrupt, 4);
+        timer.write_u8(0xFF04, 0);
+        assert_eq!(timer.read_u8(0xFF05), 0x10);
+
+        run_until(&mut timer, &mut interrupt, 12);
+        timer.write_u8(0xFF04, 0);
+        assert_eq!(timer.read_u8(0xFF05), 0x11);
+    }
+
+    #[test]
+    fn tac_change_causes_falling_edge() {
+        let (mut timer, mut interrupt) = start_timer(0x05, 0x10, 0x00, false);
+
+        // Counter bit 3 is set, bit 9 of the 4096 Hz clock isn't
+        run_until(&mut timer, &mut interrupt, 8);
+        timer.write_u8(0xFF07, 0x04);
+        assert_eq!(timer.read_u8(0xFF05), 0x11);
+
+        // Disabling the timer while the selected bit is set is also a falling edge
+        let (mut timer, mut interrupt) = start_timer(0x05, 0x10, 0x00, false);
+        run_until(&mut timer, &mut interrupt, 8);
+        timer.write_u8(0xFF07, 0x01);
+        assert_eq!(timer.read_u8(0xFF05), 0x11);
+    }
+
+    #[test]
+    fn cgb_tac_disable_glitch() {
+        // At counter 0x200 bit 3 is clear and bit 9 is set, so disabling the timer while switching
+        // to the 4096 Hz clock only increments TIMA on CGB
+        for (cgb_mode, expected_tima) in [(false, 0x30), (true, 0x31)] {
+            let (mut timer, mut interrupt) = start_timer(0x05, 0x10, 0x00, cgb_mode);
+
+            run_until(&mut timer, &mut interrupt, 0x200);
+            assert_eq!(timer.read_u8(0xFF05), 0x30);
 
-            *cycle_buffer -= 1;
+            timer.write_u8(0xFF07, 0x00);
+            assert_eq!(timer.read_u8(0xFF05), expected_tima);
         }
     }
 }
@@ -1,8 +1,12 @@
-pub type Tile = [u8; 16];
-
 pub struct VRam {
     data: [[u8; 0x2000]; 2],
     vram_bank: u8, // FF4F
+    pub cgb_mode: bool,
+    dma_source: u16,      // HDMA1/HDMA2, the low 4 bits are ignored
+    dma_destination: u16, // HDMA3/HDMA4, offset into VRAM, the low 4 bits are ignored
+    dma_length: u8,       // HDMA5, blocks of 16 bytes left to copy minus 1
+    pub is_general_dma_requested: bool,
+    pub is_hblank_dma_active: bool,
 }
 
 impl VRam {
@@ -10,12 +14,21 @@ impl VRam {
         VRam {
             data: [[0; 0x2000]; 2],
             vram_bank: 0,
+            cgb_mode: false,
+            dma_source: 0,
+            dma_destination: 0,
+            dma_length: 0x7F,
+            is_general_dma_requested: false,
+            is_hblank_dma_active: false,
         }
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
         match address {
             0xFF4F => self.vram_bank,
+            0xFF51..=0xFF54 => 0xFF, // Write only
+            // Bit 7 reads 0 while an HBlank DMA is running, all 1s once a transfer is done
+            0xFF55 => ((!self.is_hblank_dma_active as u8) << 7) | self.dma_length,
             0x8000..=0x9FFF => self.data[self.vram_bank as usize][(address - 0x8000) as usize],
             _ => panic!("Invalid VRam Read address: 0x{:04X}", address),
         }
@@ -23,7 +36,34 @@ impl VRam {
 
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match address {
-            0xFF4F => self.vram_bank = value,
+            // DMG only has bank 0
+            0xFF4F if self.cgb_mode => self.vram_bank = value & 0x01,
+            0xFF4F => {}
+            0xFF51 if self.cgb_mode => {
+                self.dma_source = (self.dma_source & 0x00FF) | ((value as u16) << 8)
+            }
+            0xFF52 if self.cgb_mode => {
+                self.dma_source = (self.dma_source & 0xFF00) | (value & 0xF0) as u16
+            }
+            0xFF53 if self.cgb_mode => {
+                self.dma_destination =
+                    (self.dma_destination & 0x00FF) | (((value & 0x1F) as u16) << 8)
+            }
+            0xFF54 if self.cgb_mode => {
+                self.dma_destination = (self.dma_destination & 0x1F00) | (value & 0xF0) as u16
+            }
+            0xFF55 if self.cgb_mode => {
+                if self.is_hblank_dma_active && value & 0x80 == 0 {
+                    // Clearing bit 7 cancels an HBlank DMA, the remaining length stays readable
+                    self.is_hblank_dma_active = false;
+                } else {
+                    // The bus copies the blocks, all at once or one per HBlank
+                    self.dma_length = value & 0x7F;
+                    self.is_hblank_dma_active = value & 0x80 != 0;
+                    self.is_general_dma_requested = value & 0x80 == 0;
+                }
+            }
+            0xFF51..=0xFF55 => {}
             0x8000..=0x9FFF => {
                 self.data[self.vram_bank as usize][(address - 0x8000) as usize] = value
             }
@@ -31,6 +71,25 @@ impl VRam {
         }
     }
 
+    // Returns the source address and VRAM offset of the next block of a VRAM DMA
+    pub fn get_dma_block(&self) -> (u16, u16) {
+        (self.dma_source, self.dma_destination)
+    }
+
+    // Moves on to the next block, the transfer ends after the last block or at the end of VRAM
+    pub fn advance_dma(&mut self) {
+        self.dma_source = self.dma_source.wrapping_add(16);
+        self.dma_destination += 16;
+        self.dma_length = self.dma_length.wrapping_sub(1) & 0x7F;
+
+        if self.dma_length == 0x7F || self.dma_destination >= 0x2000 {
+            self.dma_destination &= 0x1FF0;
+            self.dma_length = 0x7F;
+            self.is_general_dma_requested = false;
+            self.is_hblank_dma_active = false;
+        }
+    }
+
     pub fn get_tile_map(&self, address: u16) -> [u8; 0x400] {
         let mapped_address = (address - 0x8000) as usize;
 
@@ -42,34 +101,34 @@ impl VRam {
         tile_map
     }
 
-    pub fn get_tile_map_2d(&self, address: u16) -> [[u8; 32]; 32] {
-        let mapped_address = (address - 0x8000) as usize;
+    // PPU fetches ignore the selected bank, tile maps are in bank 0
+    pub fn get_tile_map_index(&self, tile_map_area: u16, tile_x: u8, tile_y: u8) -> u8 {
+        let mapped_address = (tile_map_area - 0x8000) as usize;
 
-        let mut tile_map = [[0; 32]; 32];
+        self.data[0][mapped_address + (tile_y as usize * 32) + tile_x as usize]
+    }
 
-        for row_index in 0..32 {
-            for col_index in 0..32 {
-                tile_map[row_index][col_index] = self.data[self.vram_bank as usize]
-                    [mapped_address + (row_index * 32) + col_index]
-            }
-        }
+    // CGB keeps the attributes of each tile map entry at the same address in bank 1
+    pub fn get_tile_map_attributes(&self, tile_map_area: u16, tile_x: u8, tile_y: u8) -> u8 {
+        let mapped_address = (tile_map_area - 0x8000) as usize;
 
-        tile_map
+        self.data[1][mapped_address + (tile_y as usize * 32) + tile_x as usize]
     }
 
-    pub fn get_tile(&self, offset: u16, tile_index: u8) -> Tile {
+    // Returns the low and high bytes of a row of a tile
+    pub fn get_tile_row(&self, bank: u8, tile_data_area: u16, tile_index: u8, row: u8) -> (u8, u8) {
         let tile_address: usize = match tile_index {
-            0..=127 => ((offset - 0x8000) + ((tile_index as u16) * 16)) as usize,
+            0..=127 => ((tile_data_area - 0x8000) + ((tile_index as u16) * 16)) as usize,
             128..=255 => (tile_index as usize) * 16,
         };
+        let row_address = tile_address + (row as usize * 2);
 
-        let mut tile = [0; 16];
-        tile.clone_from_slice(
-            &self.data[self.vram_bank as usize]
-                [tile_address as usize..(tile_address as usize + 16)],
-        );
+        let bank = bank as usize;
 
-        tile
+        (
+            self.data[bank][row_address],
+            self.data[bank][row_address + 1],
+        )
     }
 
     pub fn process_tile(&self, tile: [u8; 16]) -> [[u8; 8]; 8] {
@@ -91,14 +150,4 @@ impl VRam {
 
         processed_tile
     }
-
-    pub fn get_tile_pixel(&self, tile: [u8; 16], x_offset: usize, y_offset: usize) -> u8 {
-        let low_byte = tile[y_offset * 2];
-        let high_byte = tile[(y_offset * 2) + 1];
-
-        let low_bit = (low_byte >> (7 - x_offset)) & 0x1;
-        let high_bit = (high_byte >> (7 - x_offset)) & 0x1;
-
-        (high_bit << 1) | low_bit
-    }
 }
@@ -1,24 +1,41 @@
-use std::{env, time::Instant};
+use std::{
+    env,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+mod audio;
+mod audio_debugger;
 mod bus;
 mod cartridge;
 mod cpu;
 mod display;
+mod error;
+mod headless;
 mod hram;
 mod io;
 mod memory;
 mod opcode;
 mod ppu;
+mod recording;
+mod scheduler;
 mod wram;
 
+use audio::AudioOutput;
+use audio_debugger::AudioDebugger;
 use bus::Bus;
+use cartridge::gbs::GbsFile;
 
 // use cartridge::Cartridge::Cartridge;
 
 use display::{get_scroll_data, ObjectAttributeData};
+use headless::{gbs2wav, run_headless};
+use io::compatibility_palettes::ManualPalette;
 use io::lcd::PaletteData;
+use io::sgb::{SgbFrameBuffer, SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
 use macroquad::prelude::*;
-use ppu::FrameBuffer;
+use ppu::{FrameBuffer, CGB_LCD_OFF_COLOR, DMG_SHADE_COLORS};
+use recording::{finish_recording, record_frame, start_recording, AudioRecorder};
 
 fn macroquad_window_conf() -> Conf {
     Conf {
@@ -30,40 +47,355 @@ fn macroquad_window_conf() -> Conf {
     }
 }
 
+const CPU_CLOCK_HZ: f64 = 4_194_304.;
+const SGB_SCALE: f32 = 3.;
+const DEFAULT_HEADLESS_FRAMES: u32 = 60 * 60;
+
 type ProcessedTile = [[u8; 8]; 8];
 type ProcessedTiles = [[ProcessedTile; 32]; 32];
 
-#[macroquad::main(macroquad_window_conf)]
-async fn main() {
-    set_default_filter_mode(FilterMode::Nearest);
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let Some(filename) = args.get(1) else {
+        println!("Usage: rustboy2 <rom or .gbs file> [options]");
+        return;
+    };
+
+    if GbsFile::is_gbs_file(filename) {
+        run_gbs(&args);
+        return;
+    }
+
+    let mut bus = create_bus(&args);
 
+    // --record-audio out.wav records from the start, --record-channels adds a file per channel
+    let record_channels = args.iter().any(|arg| arg == "--record-channels");
+    let recorder = args
+        .iter()
+        .position(|arg| arg == "--record-audio")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|path| start_recording(path, record_channels));
+
+    // Runs without a window for --frames=<count> frames, a minute by default
+    if args.iter().any(|arg| arg == "--headless") {
+        run_headless(&mut bus, get_headless_frames(&args), recorder);
+    } else {
+        macroquad::Window::from_config(macroquad_window_conf(), run_window(bus, args, recorder));
+    }
+}
+
+fn create_bus(args: &[String]) -> Bus {
     // Read CL Args
-    let args: Vec<String> = env::args().collect();
     let rom_filename: &String = &args[1];
+    let strict_mode = args.iter().any(|arg| arg == "--strict");
+    let ppu_access_locking = !args.iter().any(|arg| arg == "--no-ppu-locking");
+    // DMG games can run on a CGB, optionally with one of the palettes picked by button combination
+    let manual_palette = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--dmg-palette="))
+        .and_then(ManualPalette::from_name);
+    let is_cgb_hardware = manual_palette.is_some() || args.iter().any(|arg| arg == "--cgb");
+    let is_sgb_hardware = !is_cgb_hardware && args.iter().any(|arg| arg == "--sgb");
+    let high_pass_filter = is_high_pass_filter_enabled(args);
 
     println!("Rom Filename: {}", rom_filename);
 
     let mut bus = Bus::new(rom_filename);
+    bus.strict_mode = strict_mode;
+    bus.ppu_access_locking = ppu_access_locking;
+    bus.io.sound.mixer.is_high_pass_filter_enabled = high_pass_filter;
+    if is_cgb_hardware {
+        bus.enable_dmg_compatibility(manual_palette);
+    }
+    if is_sgb_hardware {
+        bus.enable_sgb();
+    }
+
+    bus
+}
+
+fn get_headless_frames(args: &[String]) -> u32 {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix("--frames="))
+        .and_then(|frames| frames.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_HEADLESS_FRAMES)
+}
+
+// The hardware's output capacitors filter out DC offset, --no-high-pass leaves the raw DAC output
+fn is_high_pass_filter_enabled(args: &[String]) -> bool {
+    !args.iter().any(|arg| arg == "--no-high-pass")
+}
+
+fn get_volume(args: &[String]) -> f32 {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix("--volume="))
+        .and_then(|percent| percent.parse::<f32>().ok())
+        .map_or(1., |percent| (percent / 100.).clamp(0., 1.))
+}
+
+// GBS music files play without a game screen, --track=<number> picks the song to start with
+fn run_gbs(args: &[String]) {
+    let gbs = match GbsFile::load(&args[1]) {
+        Ok(gbs) => gbs,
+        Err(error) => {
+            println!("[Error] {}", error);
+            return;
+        }
+    };
+    let track = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--track="))
+        .and_then(|track| track.parse::<u8>().ok())
+        .filter(|track| (1..=gbs.song_count).contains(track));
+
+    println!(
+        "GBS: {} - {} ({} songs)",
+        gbs.title, gbs.author, gbs.song_count
+    );
+
+    // --gbs2wav records every song, or only the --track one, for --frames=<count> frames each
+    if args.iter().any(|arg| arg == "--gbs2wav") {
+        let record_channels = args.iter().any(|arg| arg == "--record-channels");
+        gbs2wav(
+            &gbs,
+            &args[1],
+            track,
+            get_headless_frames(args),
+            record_channels,
+            is_high_pass_filter_enabled(args),
+        );
+    } else {
+        let track = track.unwrap_or(gbs.first_song);
+        macroquad::Window::from_config(
+            macroquad_window_conf(),
+            run_gbs_window(gbs, track, args.to_vec()),
+        );
+    }
+}
+
+async fn run_window(mut bus: Bus, args: Vec<String>, mut recorder: Option<AudioRecorder>) {
+    set_default_filter_mode(FilterMode::Nearest);
+
+    let (game_width, game_height) = get_game_screen_size(bus.io.sgb.is_enabled);
+    if bus.io.sgb.is_enabled {
+        request_new_screen_size(game_width, game_height);
+    }
+
+    let volume = get_volume(&args);
+    let record_channels = args.iter().any(|arg| arg == "--record-channels");
 
     let mut frame_counter: u32 = 0;
     let mut fps_display = String::new();
     let DISPLAY_FPS = false;
 
-    let mut instant_time = Instant::now();
+    let mut audio = AudioOutput::new(volume);
+    let mut audio_debugger = AudioDebugger::default();
+    let mut sync_point = (Instant::now(), bus.cycles_elapsed());
 
     while !is_key_down(KeyCode::Escape) {
         get_input(&mut bus);
         frame_counter = frame_counter.wrapping_add(1);
-        let should_update_frame = bus.run_cycle();
+        let should_update_frame = match bus.run_cycle() {
+            Ok(should_update_frame) => should_update_frame,
+            Err(error) => {
+                println!("[Error] {}", error);
+                break;
+            }
+        };
 
         if should_update_frame {
-            draw_macroquad_frame(bus.ppu.frame_buffer);
+            // Toggles are only checked once a frame, keys count as pressed for the whole frame
+            if let Some(audio) = &mut audio {
+                get_audio_input(audio);
+            }
+            // R starts and stops recording to a file named after the rom and the time
+            if is_key_pressed(KeyCode::R) {
+                match recorder.take() {
+                    Some(active_recorder) => finish_recording(active_recorder),
+                    None => {
+                        let path = get_recording_path(&args[1]);
+                        recorder = start_recording(&path, record_channels);
+                    }
+                }
+            }
+            get_audio_debugger_input(&mut audio_debugger, &mut bus, game_width, game_height);
+
+            // The SGB keeps showing its last frame while the LCD is off
+            if bus.io.sgb.is_enabled {
+                draw_sgb_frame(&bus.io.sgb.frame_buffer);
+            } else if bus.ppu.is_lcd_off_frame {
+                draw_lcd_off_frame(bus.io.cgb_mode || bus.io.lcd.dmg_compatibility);
+            } else {
+                draw_macroquad_frame(bus.ppu.frame_buffer);
+            }
             draw_fps(DISPLAY_FPS, frame_counter, &mut fps_display);
 
+            // Without an audio device the samples are dropped and emulation runs on the wall clock
+            let samples = bus.take_audio_samples();
+            let channel_samples = bus.take_audio_channel_samples();
+            record_frame(&mut recorder, &samples, &channel_samples);
+            audio_debugger.update(&channel_samples);
+            if audio_debugger.is_visible {
+                audio_debugger.draw(game_width, &bus.read_sound_registers());
+            }
+            let speed = match &mut audio {
+                Some(audio) => {
+                    audio.push_samples(&samples);
+                    audio.get_speed()
+                }
+                None => 1.,
+            };
+
             next_frame().await;
-            instant_time = limit_60_fps(instant_time);
+            sync_point = limit_emulation_speed(sync_point, bus.cycles_elapsed(), speed);
         }
     }
+
+    if let Some(recorder) = recorder {
+        finish_recording(recorder);
+    }
+}
+
+// Left and right switch songs, which restarts the player from the song's init call
+async fn run_gbs_window(gbs: GbsFile, mut track: u8, args: Vec<String>) {
+    let (game_width, game_height) = get_game_screen_size(false);
+    let high_pass_filter = is_high_pass_filter_enabled(&args);
+    let create_gbs_bus = |track: u8| {
+        let mut bus = Bus::new_gbs(&gbs, track - 1);
+        bus.io.sound.mixer.is_high_pass_filter_enabled = high_pass_filter;
+        bus
+    };
+
+    let mut bus = create_gbs_bus(track);
+    let mut audio = AudioOutput::new(get_volume(&args));
+    let mut audio_debugger = AudioDebugger::default();
+    let mut sync_point = (Instant::now(), bus.cycles_elapsed());
+
+    while !is_key_down(KeyCode::Escape) {
+        let should_update_frame = match bus.run_cycle() {
+            Ok(should_update_frame) => should_update_frame,
+            Err(error) => {
+                println!("[Error] {}", error);
+                break;
+            }
+        };
+
+        if should_update_frame {
+            if let Some(audio) = &mut audio {
+                get_audio_input(audio);
+            }
+            get_audio_debugger_input(&mut audio_debugger, &mut bus, game_width, game_height);
+
+            draw_gbs_info(&gbs, track, bus.cycles_elapsed());
+
+            let samples = bus.take_audio_samples();
+            let channel_samples = bus.take_audio_channel_samples();
+            audio_debugger.update(&channel_samples);
+            if audio_debugger.is_visible {
+                audio_debugger.draw(game_width, &bus.read_sound_registers());
+            }
+            let speed = match &mut audio {
+                Some(audio) => {
+                    audio.push_samples(&samples);
+                    audio.get_speed()
+                }
+                None => 1.,
+            };
+
+            let next_track = if is_key_pressed(KeyCode::Left) {
+                Some(if track > 1 { track - 1 } else { gbs.song_count })
+            } else if is_key_pressed(KeyCode::Right) {
+                Some(if track < gbs.song_count { track + 1 } else { 1 })
+            } else {
+                None
+            };
+
+            next_frame().await;
+
+            match next_track {
+                Some(next_track) => {
+                    track = next_track;
+                    bus = create_gbs_bus(track);
+                    bus.set_muted_audio_channels(audio_debugger.get_muted_channels());
+                    sync_point = (Instant::now(), bus.cycles_elapsed());
+                }
+                None => {
+                    sync_point = limit_emulation_speed(sync_point, bus.cycles_elapsed(), speed);
+                }
+            }
+        }
+    }
+}
+
+// V shows the audio debugger to the right of the game, the number and function keys mute and solo
+// channels
+fn get_audio_debugger_input(
+    audio_debugger: &mut AudioDebugger,
+    bus: &mut Bus,
+    game_width: f32,
+    game_height: f32,
+) {
+    if is_key_pressed(KeyCode::V) {
+        audio_debugger.is_visible = !audio_debugger.is_visible;
+        let panel_width = if audio_debugger.is_visible {
+            audio_debugger::PANEL_WIDTH
+        } else {
+            0.
+        };
+        request_new_screen_size(game_width + panel_width, game_height);
+    }
+    if audio_debugger.handle_input() {
+        bus.set_muted_audio_channels(audio_debugger.get_muted_channels());
+    }
+}
+
+// Shows the GBS file's details, the song playing and how long it has been playing
+fn draw_gbs_info(gbs: &GbsFile, track: u8, cycles_elapsed: u64) {
+    let seconds = (cycles_elapsed as f64 / CPU_CLOCK_HZ) as u64;
+    let lines = [
+        (gbs.title.as_str(), 40.),
+        (gbs.author.as_str(), 28.),
+        (gbs.copyright.as_str(), 28.),
+        ("", 28.),
+        (&format!("Track {} / {}", track, gbs.song_count), 40.),
+        (&format!("{}:{:02}", seconds / 60, seconds % 60), 40.),
+        ("", 28.),
+        ("Left / Right: change track", 24.),
+    ];
+
+    draw_rectangle(0., 0., 160. * 4., 144. * 4., DARKBLUE);
+
+    let mut y = 60.;
+    for (text, font_size) in lines {
+        draw_text(text, 40., y, font_size, WHITE);
+        y += font_size + 12.;
+    }
+}
+
+// The SGB screen is drawn with its border at a smaller scale
+fn get_game_screen_size(is_sgb: bool) -> (f32, f32) {
+    if is_sgb {
+        (
+            SGB_SCREEN_WIDTH as f32 * SGB_SCALE,
+            SGB_SCREEN_HEIGHT as f32 * SGB_SCALE,
+        )
+    } else {
+        (160. * 4., 144. * 4.)
+    }
+}
+
+fn get_recording_path(rom_filename: &str) -> String {
+    let rom_name = Path::new(rom_filename)
+        .file_stem()
+        .map_or(String::from("recording"), |stem| {
+            stem.to_string_lossy().into_owned()
+        });
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    format!("{}-{}.wav", rom_name, seconds)
 }
 
 fn get_input(bus: &mut Bus) {
@@ -117,6 +449,19 @@ fn get_input(bus: &mut Bus) {
     }
 }
 
+// M toggles mute, minus and equals turn the volume down and up
+fn get_audio_input(audio: &mut AudioOutput) {
+    if is_key_pressed(KeyCode::M) {
+        audio.is_muted = !audio.is_muted;
+    }
+    if is_key_pressed(KeyCode::Minus) {
+        audio.volume = (audio.volume - 0.1).max(0.);
+    }
+    if is_key_pressed(KeyCode::Equal) {
+        audio.volume = (audio.volume + 0.1).min(1.);
+    }
+}
+
 fn draw_fps(display_fps: bool, frame_counter: u32, fps_display: &mut String) {
     if display_fps {
         if frame_counter % 6 == 0 {
@@ -126,19 +471,23 @@ fn draw_fps(display_fps: bool, frame_counter: u32, fps_display: &mut String) {
     }
 }
 
-fn draw_macroquad_frame(frame_buffer: FrameBuffer) {
-    let color_map: [Vec<u8>; 4] = [
-        vec![0x9a, 0x9e, 0x3f, 0xFF],
-        vec![0x49, 0x6b, 0x22, 0xFF],
-        vec![0x0e, 0x45, 0x0b, 0xFF],
-        vec![0x1b, 0x2a, 0x09, 0xFF],
-    ];
+// The screen shows the lightest shade, or white on CGB, while the LCD is off
+fn draw_lcd_off_frame(cgb_mode: bool) {
+    let color = if cgb_mode {
+        CGB_LCD_OFF_COLOR
+    } else {
+        DMG_SHADE_COLORS[0]
+    };
+
+    draw_macroquad_frame([[color; 160]; 144]);
+}
 
+fn draw_macroquad_frame(frame_buffer: FrameBuffer) {
     let mut color_bytes: Vec<u8> = Vec::with_capacity(160 * 144 * 4);
 
     for pixel_row in frame_buffer {
         for pixel in pixel_row {
-            color_bytes.extend_from_slice(&color_map[pixel as usize]);
+            color_bytes.extend_from_slice(&rgb555_to_rgba8(pixel));
         }
     }
 
@@ -156,11 +505,68 @@ fn draw_macroquad_frame(frame_buffer: FrameBuffer) {
     );
 }
 
-// TODO: GB technically not exactly 60 fps but this works for now
-fn limit_60_fps(instant_time: Instant) -> Instant {
-    std::thread::sleep(
-        std::time::Duration::from_secs_f64(1. / 60.).saturating_sub(instant_time.elapsed()),
+// Draws the SGB border with the Game Boy screen in the middle
+fn draw_sgb_frame(frame_buffer: &SgbFrameBuffer) {
+    let mut color_bytes: Vec<u8> = Vec::with_capacity(SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT * 4);
+
+    for pixel_row in frame_buffer {
+        for pixel in pixel_row {
+            color_bytes.extend_from_slice(&rgb555_to_rgba8(*pixel));
+        }
+    }
+
+    let texture = Texture2D::from_rgba8(
+        SGB_SCREEN_WIDTH as u16,
+        SGB_SCREEN_HEIGHT as u16,
+        &color_bytes,
     );
 
-    Instant::now()
+    draw_texture_ex(
+        &texture,
+        0.,
+        0.,
+        WHITE,
+        DrawTextureParams {
+            dest_size: Some(vec2(
+                SGB_SCREEN_WIDTH as f32 * SGB_SCALE,
+                SGB_SCREEN_HEIGHT as f32 * SGB_SCALE,
+            )),
+            ..Default::default()
+        },
+    );
+}
+
+// Scales each 5 bit channel up to 8 bits
+fn rgb555_to_rgba8(color: u16) -> [u8; 4] {
+    let scale = |channel: u16| ((channel << 3) | (channel >> 2)) as u8;
+
+    [
+        scale(color & 0x1F),
+        scale((color >> 5) & 0x1F),
+        scale((color >> 10) & 0x1F),
+        0xFF,
+    ]
+}
+
+// Sleeps until wall clock time catches up with the emulated time from the cycle counter, scaled
+// by the speed the audio buffer asks for. The sync point moves forward every frame so speed
+// changes only apply from then on
+// Resyncs if emulation falls too far behind, rather than running fast to catch up
+fn limit_emulation_speed(
+    sync_point: (Instant, u64),
+    cycles_elapsed: u64,
+    speed: f64,
+) -> (Instant, u64) {
+    let (sync_time, sync_cycles) = sync_point;
+    let emulated_time =
+        Duration::from_secs_f64((cycles_elapsed - sync_cycles) as f64 / (CPU_CLOCK_HZ * speed));
+    let wall_time = sync_time.elapsed();
+
+    if wall_time > emulated_time + Duration::from_millis(100) {
+        return (Instant::now(), cycles_elapsed);
+    }
+
+    std::thread::sleep(emulated_time.saturating_sub(wall_time));
+
+    (sync_time + emulated_time, cycles_elapsed)
 }
@@ -13,6 +13,15 @@ pub enum MemoryLocation {
     InterruptEnableRegister,
 }
 
+// Cartridge and work RAM share the external bus, VRAM has its own, everything from OAM up is
+// inside the CPU
+#[derive(PartialEq)]
+pub enum MemoryBus {
+    External,
+    Video,
+    Internal,
+}
+
 impl MemoryLocation {
     pub fn parse_address(address: u16) -> MemoryLocation {
         match address {
@@ -28,7 +37,24 @@ impl MemoryLocation {
             0xFF00..=0xFF7F => MemoryLocation::IO,
             0xFF80..=0xFFFE => MemoryLocation::HRam,
             0xFFFF => MemoryLocation::InterruptEnableRegister,
-            _ => panic!("Invalid Memory Location")
+            _ => panic!("Invalid Memory Location"),
+        }
+    }
+
+    pub fn get_memory_bus(&self) -> MemoryBus {
+        match self {
+            MemoryLocation::Bank0
+            | MemoryLocation::BankN
+            | MemoryLocation::ExternalRam
+            | MemoryLocation::WorkRamBank0
+            | MemoryLocation::WorkRamBankN
+            | MemoryLocation::EchoRam => MemoryBus::External,
+            MemoryLocation::VRam => MemoryBus::Video,
+            MemoryLocation::Oam
+            | MemoryLocation::NotUsed
+            | MemoryLocation::IO
+            | MemoryLocation::HRam
+            | MemoryLocation::InterruptEnableRegister => MemoryBus::Internal,
         }
     }
-}
\ No newline at end of file
+}
@@ -51,7 +51,10 @@ impl DestinationRegister8Bit {
         }
     }
 
-    fn get_value_from_register(destination_register: &DestinationRegister8Bit, bus: &Bus) -> u8 {
+    fn get_value_from_register(
+        destination_register: &DestinationRegister8Bit,
+        bus: &mut Bus,
+    ) -> u8 {
         match destination_register {
             DestinationRegister8Bit::B => bus.cpu.get_b(),
             DestinationRegister8Bit::C => bus.cpu.get_c(),
@@ -274,7 +277,7 @@ impl Opcode for IncrementRegister8Bit {
         }
 
         let value =
-            DestinationRegister8Bit::get_value_from_register(&self.destination_register, &bus);
+            DestinationRegister8Bit::get_value_from_register(&self.destination_register, bus);
 
         let incremented_value = value.wrapping_add(1);
 
@@ -333,7 +336,7 @@ impl Opcode for DecrementRegister8Bit {
         }
 
         let value =
-            DestinationRegister8Bit::get_value_from_register(&self.destination_register, &bus);
+            DestinationRegister8Bit::get_value_from_register(&self.destination_register, bus);
 
         self.decremented_value = value.wrapping_sub(1);
 
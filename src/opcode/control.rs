@@ -128,3 +128,33 @@ impl Opcode for Halt {
         &self.opcode_data
     }
 }
+
+// ------------------------------------------------------------------------------------------------
+
+pub struct Stop {
+    opcode_data: OpcodeData,
+}
+
+impl Stop {
+    pub fn default() -> Stop {
+        let opcode = 0x10;
+        let mnemonic = String::from("Stop");
+        let cycles = 1;
+
+        Stop {
+            opcode_data: OpcodeData::new(opcode, cycles, mnemonic),
+        }
+    }
+}
+
+impl Opcode for Stop {
+    fn execute(&mut self, bus: &mut Bus) -> () {
+        // STOP is followed by a padding byte that is skipped
+        bus.cpu.add_to_pc(1);
+        bus.stop();
+    }
+
+    fn opcode_data(&self) -> &OpcodeData {
+        &self.opcode_data
+    }
+}
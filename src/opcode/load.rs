@@ -386,7 +386,7 @@ impl Opcode for LoadRegister {
             ),
         };
 
-        bus.cpu.add_m_cycles(cycles);
+        bus.complete_m_cycles(cycles);
     }
 
     fn opcode_data(&self) -> &OpcodeData {
@@ -9,7 +9,7 @@ use super::{
         Subtract8BitRegister, Subtract8BitRegisterWithCarry, Xor8BitRegister,
     },
     cb::CB,
-    control::{DisableInterrupts, EnableInterrupts, Halt, Noop},
+    control::{DisableInterrupts, EnableInterrupts, Halt, Noop, Stop},
     jump::{
         CallAddress, CallConditional, CallRST, CallReturn, JumpAddress, JumpConditional, JumpHL,
         JumpRelative, JumpRelativeConditional, ReturnConditional, ReturnEnableInterrupts,
@@ -93,6 +93,7 @@ pub fn execute_opcode(bus: &mut Bus, opcode: u8) -> () {
         0x27 => Box::new(DecimalAdjustAccumulator::default(opcode)),
         0x2F => Box::new(ComplementAccumulator::default(opcode)),
         0x76 => Box::new(Halt::default()),
+        0x10 => Box::new(Stop::default()),
         0x37 => Box::new(SetCarryFlag::default(opcode)),
         0x3F => Box::new(ComplementCarryFlag::default(opcode)),
         0xE8 => Box::new(AddSignedImmediateToSP::default(opcode)),
@@ -130,7 +131,7 @@ pub trait Opcode {
     }
 
     fn update_cycles(&self, bus: &mut Bus) -> () {
-        bus.cpu.add_m_cycles(self.opcode_data().cycles);
+        bus.complete_m_cycles(self.opcode_data().cycles);
     }
     fn update_flags(&self, bus: &mut Bus) -> () {
         // Default Noop - No Flags to Update
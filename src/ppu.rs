@@ -1,64 +1,131 @@
-use crate::{
-    bus::{self, Bus},
-    io::{io::IO, lcd::PaletteData, oam::ObjectAttribute, vram::Tile},
-};
+use std::collections::VecDeque;
+
+use crate::io::{io::IO, oam::ObjectAttribute};
 
 pub type ScanlineObjectBuffer = [u8; 10];
-pub type ScanlineBuffer = [u8; 160];
+pub type ScanlineBuffer = [u16; 160]; // RGB555 colors
 pub type FrameBuffer = [ScanlineBuffer; 144];
+pub type ShadeBuffer = [[u8; 160]; 144]; // DMG shades after the palettes, colored by the SGB
+
+// The DMG's 4 shades as RGB555, from lightest to darkest
+pub const DMG_SHADE_COLORS: [u16; 4] = [0x1E73, 0x11A9, 0x0501, 0x04A3];
+pub const CGB_LCD_OFF_COLOR: u16 = 0x7FFF; // White
+
+// The first tile fetch of every scanline is thrown away
+const STARTUP_DOTS: u8 = 6;
+const OBJECT_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum FetcherStep {
+    TileIndex,
+    TileDataLow,
+    TileDataHigh,
+    Push,
+}
 
-pub struct BGState {
-    bg_window_tile_data_area: u16,
-    bg_palette: PaletteData,
-    bg_tile_map: [[u8; 32]; 32],
-    window_tile_map: [[u8; 32]; 32],
+// Fetches a row of 8 background or window pixels, each step except push takes 2 dots
+struct Fetcher {
+    step: FetcherStep,
+    step_dots: u8,
+    tile_x: u8, // Tiles fetched so far on this scanline, or since the window started
+    tile_index: u8,
+    tile_attributes: u8, // CGB only, read from VRAM bank 1
+    tile_data_low: u8,
+    tile_data_high: u8,
+    is_window: bool,
 }
 
-impl BGState {
-    fn default() -> BGState {
-        BGState {
-            bg_window_tile_data_area: 0,
-            bg_palette: PaletteData::default(),
-            bg_tile_map: [[0; 32]; 32],
-            window_tile_map: [[0; 32]; 32],
+impl Fetcher {
+    fn default() -> Fetcher {
+        Fetcher {
+            step: FetcherStep::TileIndex,
+            step_dots: 0,
+            tile_x: 0,
+            tile_index: 0,
+            tile_attributes: 0,
+            tile_data_low: 0,
+            tile_data_high: 0,
+            is_window: false,
         }
     }
 }
 
-pub struct OAMState {
-    object_palette_0: PaletteData,
-    object_palette_1: PaletteData,
-    is_8_height: bool,
+#[derive(Debug, Copy, Clone)]
+struct BackgroundPixel {
+    color: u8,
+    palette: u8,       // CGB only
+    is_priority: bool, // CGB only, drawn over objects
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ObjectPixel {
+    color: u8,   // 0 is transparent
+    palette: u8, // OBP0 or OBP1 on DMG, 0-7 on CGB
+    is_bg_priority: bool,
+    oam_index: u8,
 }
 
-impl OAMState {
-    fn default() -> OAMState {
-        OAMState {
-            object_palette_0: PaletteData::default(),
-            object_palette_1: PaletteData::default(),
-            is_8_height: false,
+impl ObjectPixel {
+    fn transparent() -> ObjectPixel {
+        ObjectPixel {
+            color: 0,
+            palette: 0,
+            is_bg_priority: false,
+            oam_index: 0xFF,
         }
     }
 }
 
 pub struct PPU {
     scanline_object_id_buffer: ScanlineObjectBuffer,
+    fetched_objects: [bool; 10],
     pub frame_buffer: FrameBuffer,
-    pub window_internal_line_counter: u8,
-    oam_state: OAMState,
-    bg_state: BGState,
+    pub shade_buffer: ShadeBuffer,
+    pub is_lcd_off_frame: bool, // The LCD was off for part of the frame, so it shows blank
+    window_internal_line_counter: u8, // Only advances on lines the window is drawn on
+    is_window_y_triggered: bool, // Latched once LY equals WY, until the next frame
     current_scanline: u8,
+
+    // Pixel transfer state, the FIFOs are run lazily up to the current time
+    is_rendering: bool,
+    pixel_transfer_time: u64, // Time mode 3 was entered
+    dots: u64,                // Dots rendered since mode 3 was entered
+    startup_dots: u8,
+    discard_pixels: u8, // SCX fine scroll or WX < 7, pixels shifted out before they are drawn
+    x_position: u8,     // Next pixel drawn on the scanline
+    fetcher: Fetcher,
+    bg_fifo: VecDeque<BackgroundPixel>,
+    object_fifo: VecDeque<ObjectPixel>,
+    object_fetch_dots: u8,
+    object_fetch_index: usize,
+    is_window_drawn: bool,
+    is_window_line_wrapped: bool, // WX = 166 makes the window cover the whole next scanline
 }
 
 impl PPU {
     pub fn new() -> PPU {
         PPU {
             scanline_object_id_buffer: [0xFF; 10], // 0xFF being treated as empty object
+            fetched_objects: [false; 10],
             frame_buffer: [[0; 160]; 144],
+            shade_buffer: [[0; 160]; 144],
+            is_lcd_off_frame: false,
             window_internal_line_counter: 0,
-            oam_state: OAMState::default(),
-            bg_state: BGState::default(),
+            is_window_y_triggered: false,
             current_scanline: 0,
+            is_rendering: false,
+            pixel_transfer_time: 0,
+            dots: 0,
+            startup_dots: 0,
+            discard_pixels: 0,
+            x_position: 0,
+            fetcher: Fetcher::default(),
+            bg_fifo: VecDeque::with_capacity(8),
+            object_fifo: VecDeque::with_capacity(8),
+            object_fetch_dots: 0,
+            object_fetch_index: 0,
+            is_window_drawn: false,
+            is_window_line_wrapped: false,
         }
     }
 
@@ -92,322 +159,727 @@ impl PPU {
         }
     }
 
-    pub fn update_current_scanline_in_frame_buffer(&mut self, io: &IO) {
-        self.current_scanline = io.lcd.lcd_y_coordinate;
+    // WY is only compared when a scanline starts, the window stays triggered for the rest of
+    // the frame even if WY changes
+    pub fn update_window_y_trigger(&mut self, io: &IO) {
+        let (window_scroll_y, _) = io.lcd.get_window_scroll_data();
 
-        if self.current_scanline >= 144 {
-            println!(
-                "[Warning] - Attempted to update scanline during vblank - LY {}",
-                self.current_scanline
-            );
-            return;
+        if io.lcd.lcd_y_coordinate == window_scroll_y {
+            self.is_window_y_triggered = true;
         }
+    }
+
+    // Pixel Transfer
 
-        self.update_bg_state(io);
+    pub fn start_pixel_transfer(&mut self, io: &IO, time: u64) {
+        let (_, bg_scroll_x) = io.lcd.get_scroll_data();
 
-        // LCD Enable
-        if !io.lcd.is_lcd_enabled() {
-            for pixel_index in 0..160_u8 {
-                self.frame_buffer[self.current_scanline as usize][pixel_index as usize] = 0x00;
+        self.current_scanline = io.lcd.lcd_y_coordinate;
+        self.is_rendering = true;
+        self.pixel_transfer_time = time;
+        self.dots = 0;
+        self.startup_dots = STARTUP_DOTS;
+        self.discard_pixels = bg_scroll_x % 8;
+        self.x_position = 0;
+        self.fetcher = Fetcher::default();
+        self.bg_fifo.clear();
+        self.object_fifo.clear();
+        self.object_fetch_dots = 0;
+        self.fetched_objects = [false; 10];
+        self.is_window_drawn = false;
+
+        if self.is_window_line_wrapped {
+            self.is_window_line_wrapped = false;
+
+            if io.lcd.is_window_enabled() {
+                self.discard_pixels = 0;
+                self.start_window(io);
             }
         }
+    }
 
-        // Background
-        if io.lcd.is_background_enabled() {
-            self.render_background(io);
-        } else {
-            self.clear_background();
+    // Renders up to the given time, called before the CPU changes anything the PPU reads so
+    // mid-scanline writes take effect on the right pixel
+    pub fn sync(&mut self, io: &IO, now: u64) {
+        if !self.is_rendering {
+            return;
         }
 
-        // Window
-        let (window_scroll_y, window_scroll_x) = io.lcd.get_window_scroll_data();
+        let target_dots = now.saturating_sub(self.pixel_transfer_time);
 
-        if io.lcd.is_window_enabled()
-            && io.lcd.is_background_enabled()
-            && self.current_scanline >= window_scroll_y
-            && window_scroll_y < 144
-        {
-            self.render_window(io, window_scroll_x);
+        while self.dots < target_dots && self.x_position < 160 {
+            self.render_dot(io);
         }
+    }
 
-        // Objects
-        if io.lcd.is_object_enabled() {
-            let object_attributes = self.get_object_attributes(io);
+    // Renders whatever is left of the scanline when HBlank starts
+    pub fn finish_scanline(&mut self, io: &IO) {
+        if !self.is_rendering {
+            return;
+        }
 
-            self.update_oam_state(io);
+        while self.x_position < 160 {
+            self.render_dot(io);
+        }
 
-            // Iterates in reverse for object priority
-            for object_attribute in object_attributes.iter().rev() {
-                self.render_object(object_attribute, io);
-            }
+        self.is_rendering = false;
+
+        if self.is_window_drawn {
+            self.window_internal_line_counter += 1;
         }
     }
 
-    // General Helper Functions
+    // Drops the scanline being drawn, the screen stays blank until a whole frame is drawn
+    pub fn turn_off(&mut self) {
+        self.is_rendering = false;
+        self.x_position = 160;
+        self.is_lcd_off_frame = true;
+        self.reset_window();
+    }
 
-    fn update_bg_state(&mut self, io: &IO) -> () {
-        let bg_tile_map = io.vram.get_tile_map_2d(io.lcd.bg_tile_map_area());
-        let bg_window_tile_data_area = io.lcd.bg_window_tile_data_area();
-        let bg_palette = io.lcd.get_palette_data();
-        let window_tile_map = io.vram.get_tile_map_2d(io.lcd.window_tile_map_area());
+    // The window state only carries over between scanlines of the same frame
+    pub fn reset_window(&mut self) {
+        self.window_internal_line_counter = 0;
+        self.is_window_y_triggered = false;
+        self.is_window_line_wrapped = false;
+    }
 
-        self.bg_state = BGState {
-            bg_window_tile_data_area,
-            bg_palette,
-            bg_tile_map,
-            window_tile_map,
+    // Fewest dots the scanline could still take to draw, None once it is finished
+    pub fn get_min_dots_remaining(&self) -> Option<u64> {
+        if self.x_position >= 160 {
+            return None;
         }
-    }
 
-    fn get_tile(&self, io: &IO, scrolled_x: u8, scrolled_y: u8, is_bg: bool) -> Tile {
-        let tile_map_x_offset = scrolled_x / 8;
-        let tile_map_y_offset = scrolled_y / 8;
+        let pixels_remaining = 160 - self.x_position as u64 + self.discard_pixels as u64;
 
-        let current_tile_index = if is_bg {
-            self.bg_state.bg_tile_map[tile_map_y_offset as usize][tile_map_x_offset as usize]
-        } else {
-            self.bg_state.window_tile_map[tile_map_y_offset as usize][tile_map_x_offset as usize]
-        };
+        Some(pixels_remaining + self.startup_dots as u64)
+    }
 
-        io.vram
-            .get_tile(self.bg_state.bg_window_tile_data_area, current_tile_index)
+    pub fn get_pixel_transfer_dots(&self) -> u64 {
+        self.dots
     }
 
-    fn render_tile_pixel(
-        &mut self,
-        io: &IO,
-        tile: Tile,
-        scrolled_x: u8,
-        scrolled_y: u8,
-        pixel_index: u8,
-    ) {
-        let tile_data_x_offset = scrolled_x % 8;
-        let tile_data_y_offset = scrolled_y % 8;
+    fn render_dot(&mut self, io: &IO) {
+        self.dots += 1;
 
-        let raw_pixel = io.vram.get_tile_pixel(
-            tile,
-            tile_data_x_offset as usize,
-            tile_data_y_offset as usize,
-        );
+        if self.startup_dots > 0 {
+            self.startup_dots -= 1;
+            return;
+        }
 
-        let palette_mapped_pixel = self.bg_state.bg_palette.get_color(raw_pixel);
+        if !self.fetcher.is_window && self.is_window_triggered(io) {
+            self.start_window(io);
+        }
 
-        self.frame_buffer[self.current_scanline as usize][pixel_index as usize] =
-            palette_mapped_pixel;
-    }
+        // Disabling the window mid scanline switches the fetcher back to the background
+        if self.fetcher.is_window && !io.lcd.is_window_enabled() {
+            self.fetcher.is_window = false;
+            self.fetcher.tile_x = self.get_background_tile_x(io);
+        }
 
-    // Background Rendering Helper Functions
+        // Object fetches stall the pixel output
+        if self.object_fetch_dots > 0 {
+            self.object_fetch_dots -= 1;
 
-    fn render_background(&mut self, io: &IO) -> () {
-        let (bg_scrolly_y, bg_scroll_x) = io.lcd.get_scroll_data();
+            if self.object_fetch_dots == 0 {
+                self.render_object(io, self.object_fetch_index);
+            }
 
-        for pixel_index in 0..160_u8 {
-            let scrolled_x: u8 = pixel_index.wrapping_add(bg_scroll_x);
-            let scrolled_y: u8 = self.current_scanline.wrapping_add(bg_scrolly_y);
+            return;
+        }
+
+        self.tick_fetcher(io);
 
-            // Get Tile Index
-            let tile = self.get_tile(io, scrolled_x, scrolled_y, true);
+        if let Some(object_fetch_index) = self.get_next_object_to_fetch(io) {
+            // The object fetch waits for the background fetch in progress to almost finish,
+            // this dot counts as the first dot of the object fetch
+            if self.is_fetcher_ready_for_object() {
+                self.object_fetch_index = object_fetch_index;
+                self.object_fetch_dots = OBJECT_FETCH_DOTS - 1;
+            }
 
-            // Get Tile Pixel
-            self.render_tile_pixel(io, tile, scrolled_x, scrolled_y, pixel_index);
+            return;
         }
+
+        self.shift_out_pixel(io);
     }
 
-    fn clear_background(&mut self) -> () {
-        for pixel_index in 0..160_u8 {
-            self.frame_buffer[self.current_scanline as usize][pixel_index as usize] = 0x00;
+    fn shift_out_pixel(&mut self, io: &IO) {
+        let bg_pixel = match self.bg_fifo.pop_front() {
+            Some(bg_pixel) => bg_pixel,
+            None => return,
+        };
+
+        if self.discard_pixels > 0 {
+            self.discard_pixels -= 1;
+            return;
         }
+
+        let object_pixel = self.object_fifo.pop_front();
+        let pixel = if io.cgb_mode {
+            self.mix_cgb_pixel(io, bg_pixel, object_pixel)
+        } else {
+            let (shade, color) = self.mix_dmg_pixel(io, bg_pixel.color, object_pixel);
+            self.shade_buffer[self.current_scanline as usize][self.x_position as usize] = shade;
+            color
+        };
+
+        self.frame_buffer[self.current_scanline as usize][self.x_position as usize] = pixel;
+        self.x_position += 1;
     }
 
-    // Window Rendering Helper Functions
+    // Returns the shade and its color
+    fn mix_dmg_pixel(&self, io: &IO, bg_color: u8, object_pixel: Option<ObjectPixel>) -> (u8, u16) {
+        // Background and window are blank while disabled, objects are still drawn
+        let bg_color = if io.lcd.is_background_enabled() {
+            bg_color
+        } else {
+            0
+        };
 
-    fn render_window(&mut self, io: &IO, window_scroll_x: u8) -> () {
-        for pixel_index in 0..160 {
-            self.render_window_pixel(io, window_scroll_x, pixel_index);
+        // DMG games on a CGB look their shades up in the palettes loaded by the boot ROM
+        match object_pixel {
+            Some(object_pixel)
+                if object_pixel.color != 0 && (!object_pixel.is_bg_priority || bg_color == 0) =>
+            {
+                let shade = self.map_pixel_by_palette(io, object_pixel);
+
+                if io.lcd.dmg_compatibility {
+                    (shade, io.lcd.get_object_color(object_pixel.palette, shade))
+                } else {
+                    (shade, DMG_SHADE_COLORS[shade as usize])
+                }
+            }
+            _ => {
+                let shade = if io.lcd.is_background_enabled() {
+                    io.lcd.get_palette_data().get_color(bg_color)
+                } else {
+                    0x00
+                };
+
+                if io.lcd.dmg_compatibility {
+                    (shade, io.lcd.get_bg_color(0, shade))
+                } else {
+                    (shade, DMG_SHADE_COLORS[shade as usize])
+                }
+            }
         }
+    }
 
-        if window_scroll_x < 166 {
-            self.window_internal_line_counter += 1;
+    fn mix_cgb_pixel(
+        &self,
+        io: &IO,
+        bg_pixel: BackgroundPixel,
+        object_pixel: Option<ObjectPixel>,
+    ) -> u16 {
+        let object_pixel = object_pixel.unwrap_or(ObjectPixel::transparent());
+
+        // On CGB LCDC bit 0 takes priority away from the background instead of hiding it
+        let is_bg_over_object = io.lcd.is_background_enabled()
+            && bg_pixel.color != 0
+            && (bg_pixel.is_priority || object_pixel.is_bg_priority);
+
+        if object_pixel.color != 0 && !is_bg_over_object {
+            io.lcd
+                .get_object_color(object_pixel.palette, object_pixel.color)
+        } else {
+            io.lcd.get_bg_color(bg_pixel.palette, bg_pixel.color)
         }
     }
 
-    fn render_window_pixel(&mut self, io: &IO, window_scroll_x: u8, pixel_index: u8) {
-        // Pixel outside of window
-        if (pixel_index + 7 < (window_scroll_x)) || window_scroll_x > 166 {
+    // Background and Window Fetching
+
+    fn tick_fetcher(&mut self, io: &IO) {
+        if self.fetcher.step == FetcherStep::Push {
+            // Only pushes once the FIFO is empty
+            if self.bg_fifo.is_empty() {
+                self.push_tile_row();
+                self.fetcher.tile_x = self.fetcher.tile_x.wrapping_add(1);
+                self.fetcher.step = FetcherStep::TileIndex;
+            }
+
             return;
         }
 
-        let scrolled_x: u8 = pixel_index + 7 - window_scroll_x;
-        let scrolled_y: u8 = self.window_internal_line_counter;
-
-        let tile = self.get_tile(io, scrolled_x, scrolled_y, false);
+        self.fetcher.step_dots += 1;
+        if self.fetcher.step_dots < 2 {
+            return;
+        }
 
-        // Get Tile Pixel
-        self.render_tile_pixel(io, tile, scrolled_x, scrolled_y, pixel_index);
+        self.fetcher.step_dots = 0;
+        self.fetcher.step = match self.fetcher.step {
+            FetcherStep::TileIndex => {
+                self.fetcher.tile_index = self.fetch_tile_index(io);
+                self.fetcher.tile_attributes = self.fetch_tile_attributes(io);
+                FetcherStep::TileDataLow
+            }
+            FetcherStep::TileDataLow => {
+                let (tile_data_low, _) = self.fetch_tile_row(io);
+                self.fetcher.tile_data_low = tile_data_low;
+                FetcherStep::TileDataHigh
+            }
+            FetcherStep::TileDataHigh => {
+                let (_, tile_data_high) = self.fetch_tile_row(io);
+                self.fetcher.tile_data_high = tile_data_high;
+                FetcherStep::Push
+            }
+            FetcherStep::Push => FetcherStep::Push,
+        };
     }
 
-    // Object Rendering Helper Functions
+    fn is_fetcher_ready_for_object(&self) -> bool {
+        if self.bg_fifo.is_empty() {
+            return false;
+        }
 
-    fn update_oam_state(&mut self, io: &IO) -> () {
-        let object_palette_0 = io.lcd.get_object_palette_0_data();
-        let object_palette_1 = io.lcd.get_object_palette_1_data();
+        match self.fetcher.step {
+            FetcherStep::TileDataHigh => self.fetcher.step_dots == 1,
+            FetcherStep::Push => true,
+            _ => false,
+        }
+    }
+
+    // Returns the tile map area and the position of the tile being fetched
+    fn get_tile_map_position(&self, io: &IO) -> (u16, u8, u8) {
+        if self.fetcher.is_window {
+            let tile_y = self.window_internal_line_counter / 8;
 
-        let is_8_height = io.lcd.is_8_height();
+            (io.lcd.window_tile_map_area(), self.fetcher.tile_x, tile_y)
+        } else {
+            let (bg_scroll_y, bg_scroll_x) = io.lcd.get_scroll_data();
+            let tile_x = (bg_scroll_x / 8).wrapping_add(self.fetcher.tile_x) % 32;
+            let tile_y = self.current_scanline.wrapping_add(bg_scroll_y) / 8;
 
-        self.oam_state = OAMState {
-            object_palette_0,
-            object_palette_1,
-            is_8_height,
+            (io.lcd.bg_tile_map_area(), tile_x, tile_y)
         }
     }
 
-    fn render_object(&mut self, object_attribute: &ObjectAttribute, io: &IO) -> () {
-        // Do not render object if completely (all 8 pixels) outside of screen width
-        if object_attribute.x == 0 || object_attribute.x >= 168 {
-            return;
-        }
+    // Background tile, counted from SCX / 8, of the first pixel the fetch in progress pushes. It
+    // is pushed after the pixels still queued, so the background picks up from the next whole tile
+    fn get_background_tile_x(&self, io: &IO) -> u8 {
+        let (_, bg_scroll_x) = io.lcd.get_scroll_data();
+        let next_pixel = (self.x_position as usize + self.bg_fifo.len())
+            .saturating_sub(self.discard_pixels as usize)
+            + (bg_scroll_x % 8) as usize;
 
-        let (object_tile_lower, object_tile_higher) = self.get_object_tiles(io, object_attribute);
+        next_pixel.div_ceil(8) as u8
+    }
 
-        let y_pixel = (self.current_scanline as i16 - (object_attribute.y as i16 - 16))
-            % (if self.oam_state.is_8_height { 8 } else { 16 });
+    fn fetch_tile_index(&self, io: &IO) -> u8 {
+        let (tile_map_area, tile_x, tile_y) = self.get_tile_map_position(io);
 
-        if y_pixel < 0 {
-            return;
+        io.vram.get_tile_map_index(tile_map_area, tile_x, tile_y)
+    }
+
+    fn fetch_tile_attributes(&self, io: &IO) -> u8 {
+        if !io.cgb_mode {
+            return 0;
         }
 
+        let (tile_map_area, tile_x, tile_y) = self.get_tile_map_position(io);
+
+        io.vram
+            .get_tile_map_attributes(tile_map_area, tile_x, tile_y)
+    }
+
+    fn fetch_tile_row(&self, io: &IO) -> (u8, u8) {
+        let tile_row = if self.fetcher.is_window {
+            self.window_internal_line_counter % 8
+        } else {
+            let (bg_scroll_y, _) = io.lcd.get_scroll_data();
+            self.current_scanline.wrapping_add(bg_scroll_y) % 8
+        };
+
+        let tile_attributes = self.fetcher.tile_attributes;
+        let tile_row = if tile_attributes & 0x40 != 0 {
+            7 - tile_row
+        } else {
+            tile_row
+        };
+
+        io.vram.get_tile_row(
+            (tile_attributes & 0x08) >> 3,
+            io.lcd.bg_window_tile_data_area(),
+            self.fetcher.tile_index,
+            tile_row,
+        )
+    }
+
+    fn push_tile_row(&mut self) {
+        let tile_attributes = self.fetcher.tile_attributes;
+
         for pixel_index in 0..8 {
-            let in_window = self.render_object_pixel(
-                io,
-                object_attribute,
-                object_tile_lower,
-                object_tile_higher,
-                pixel_index,
-                y_pixel as u8,
-            );
-
-            if !in_window {
-                break;
+            let x_offset = if tile_attributes & 0x20 != 0 {
+                7 - pixel_index
+            } else {
+                pixel_index
             };
+
+            self.bg_fifo.push_back(BackgroundPixel {
+                color: PPU::get_pixel_color(
+                    self.fetcher.tile_data_low,
+                    self.fetcher.tile_data_high,
+                    x_offset,
+                ),
+                palette: tile_attributes & 0x07,
+                is_priority: tile_attributes & 0x80 != 0,
+            });
         }
     }
 
-    fn render_object_pixel(
-        &mut self,
-        io: &IO,
-        object_attribute: &ObjectAttribute,
-        object_tile_lower: Tile,
-        object_tile_higher: Tile,
-        pixel_index: u8,
-        y_pixel: u8,
-    ) -> bool {
-        let current_pixel_offset = object_attribute.x as i16 - 8 + pixel_index as i16;
+    fn get_pixel_color(tile_data_low: u8, tile_data_high: u8, x_offset: u8) -> u8 {
+        let low_bit = (tile_data_low >> (7 - x_offset)) & 0x1;
+        let high_bit = (tile_data_high >> (7 - x_offset)) & 0x1;
+
+        (high_bit << 1) | low_bit
+    }
+
+    // Window Helper Functions
+
+    // WX is compared against the pixel being drawn, so the window only starts on the exact
+    // pixel, WX 0-6 start it on the first pixel
+    fn is_window_triggered(&self, io: &IO) -> bool {
+        let (_, window_scroll_x) = io.lcd.get_window_scroll_data();
+        let window_start_x = window_scroll_x.saturating_sub(7);
+
+        // LCDC bit 0 only hides the window on DMG
+        io.lcd.is_window_enabled()
+            && (io.cgb_mode || io.lcd.is_background_enabled())
+            && self.is_window_y_triggered
+            && self.discard_pixels == 0
+            && self.x_position == window_start_x
+    }
+
+    // The background pixels still queued are dropped and fetching restarts from the window
+    fn start_window(&mut self, io: &IO) {
+        let (_, window_scroll_x) = io.lcd.get_window_scroll_data();
+
+        self.bg_fifo.clear();
+        self.fetcher = Fetcher::default();
+        self.fetcher.is_window = true;
+        self.is_window_drawn = true;
 
-        if current_pixel_offset < 0 {
-            return true; // Continue rendering, but this pixel is outside of screen
+        // The window's first pixels are left of the screen when WX < 7
+        if self.x_position == 0 && window_scroll_x < 7 {
+            self.discard_pixels = 7 - window_scroll_x;
         }
 
-        // Do not render pixel if outside of screen
-        // Differs from render_object check because some objects can be partially in frame
-        if current_pixel_offset >= 160 {
-            return false;
+        if window_scroll_x == 166 {
+            self.is_window_line_wrapped = true;
+        }
+    }
+
+    // Object Rendering Helper Functions
+
+    fn get_next_object_to_fetch(&self, io: &IO) -> Option<usize> {
+        if !io.lcd.is_object_enabled() {
+            return None;
         }
 
-        // Gets pixel offset for a specific tile
-        let x_pixel_offset: usize = self.get_object_x_pixel_offset(object_attribute, pixel_index);
+        // Objects left of the screen are all reached on the first pixel, lower X goes first
+        (0..10)
+            .filter(|&buffer_index| {
+                self.scanline_object_id_buffer[buffer_index] != 0xFF
+                    && !self.fetched_objects[buffer_index]
+            })
+            .map(|buffer_index| {
+                let oam_index = self.scanline_object_id_buffer[buffer_index];
+                (io.oam.get_object_attribute(oam_index).x, buffer_index)
+            })
+            .filter(|(x, _)| *x <= self.x_position + 8)
+            .min()
+            .map(|(_, buffer_index)| buffer_index)
+    }
+
+    // Fetches the object's row and merges it into the object FIFO
+    fn render_object(&mut self, io: &IO, buffer_index: usize) {
+        self.fetched_objects[buffer_index] = true;
 
-        let y_pixel_offset: usize = self.get_object_y_pixel_offset(object_attribute, y_pixel);
+        let oam_index = self.scanline_object_id_buffer[buffer_index];
+        let object_attribute = io.oam.get_object_attribute(oam_index);
 
-        let pixel = if y_pixel_offset < 8 {
-            io.vram
-                .get_tile_pixel(object_tile_lower, x_pixel_offset, y_pixel_offset)
+        // Objects completely outside of the screen width still count towards the 10 object limit,
+        // but have nothing to draw
+        if object_attribute.x == 0 || object_attribute.x >= 168 {
+            return;
+        }
+
+        let is_oam_index_priority = io.cgb_mode && io.cgb_registers.is_oam_index_priority();
+        let palette = if io.cgb_mode {
+            object_attribute.get_cgb_palette()
+        } else if object_attribute.is_obj_palette_0() {
+            0
         } else {
-            io.vram
-                .get_tile_pixel(object_tile_higher, x_pixel_offset, y_pixel_offset % 8)
+            1
         };
+        let (tile_data_low, tile_data_high) = self.get_object_tile_row(io, &object_attribute);
+
+        for pixel_index in 0..8 {
+            let current_pixel_offset = object_attribute.x as i16 - 8 + pixel_index as i16;
 
-        let palette_mapped_pixel = self.map_pixel_by_palette(object_attribute, pixel);
+            // Pixels left of the screen, or already drawn
+            if current_pixel_offset < self.x_position as i16 {
+                continue;
+            }
 
-        self.set_object_pixel(
-            palette_mapped_pixel,
-            current_pixel_offset as usize,
-            object_attribute.is_bg_priority(),
-        );
+            let x_pixel_offset = self.get_object_x_pixel_offset(&object_attribute, pixel_index);
+            let object_pixel = ObjectPixel {
+                color: PPU::get_pixel_color(tile_data_low, tile_data_high, x_pixel_offset),
+                palette,
+                is_bg_priority: object_attribute.is_bg_priority(),
+                oam_index,
+            };
 
-        true
+            let fifo_index = (current_pixel_offset - self.x_position as i16) as usize;
+            self.set_object_pixel(object_pixel, fifo_index, is_oam_index_priority);
+        }
     }
 
-    fn get_object_x_pixel_offset(
-        &self,
-        object_attribute: &ObjectAttribute,
-        pixel_index: u8,
-    ) -> usize {
+    fn get_object_x_pixel_offset(&self, object_attribute: &ObjectAttribute, pixel_index: u8) -> u8 {
         if object_attribute.is_h_flip() {
-            return 7 - pixel_index as usize;
+            7 - pixel_index
         } else {
-            return pixel_index as usize;
-        };
+            pixel_index
+        }
     }
 
-    fn get_object_y_pixel_offset(&self, object_attribute: &ObjectAttribute, y_pixel: u8) -> usize {
+    fn get_object_y_pixel_offset(
+        &self,
+        object_attribute: &ObjectAttribute,
+        object_height: u8,
+    ) -> u8 {
+        let y_pixel = (self.current_scanline + 16).wrapping_sub(object_attribute.y) % object_height;
+
         if object_attribute.is_v_flip() {
-            if self.oam_state.is_8_height {
-                return 7 - y_pixel as usize;
-            } else {
-                return 15 - y_pixel as usize;
-            }
+            object_height - 1 - y_pixel
         } else {
-            return y_pixel as usize;
+            y_pixel
+        }
+    }
+
+    fn get_object_tile_row(&self, io: &IO, object_attribute: &ObjectAttribute) -> (u8, u8) {
+        let object_height = io.lcd.object_height();
+        let y_pixel_offset = self.get_object_y_pixel_offset(object_attribute, object_height);
+
+        // The rows of 8x16 objects continue into the next tile
+        let tile_number = if io.lcd.is_8_height() {
+            object_attribute.tile_number
+        } else {
+            object_attribute.tile_number & 0xFE
+        };
+
+        let bank = if io.cgb_mode {
+            object_attribute.get_vram_bank()
+        } else {
+            0
         };
+
+        io.vram
+            .get_tile_row(bank, 0x8000, tile_number, y_pixel_offset)
     }
 
-    fn get_object_attributes(&self, io: &IO) -> Vec<ObjectAttribute> {
-        let mut object_attributes: Vec<ObjectAttribute> = Vec::new();
+    // TODO: Move to lcd probably
+    fn map_pixel_by_palette(&self, io: &IO, object_pixel: ObjectPixel) -> u8 {
+        if object_pixel.palette == 0 {
+            io.lcd
+                .get_object_palette_0_data()
+                .get_color(object_pixel.color)
+        } else {
+            io.lcd
+                .get_object_palette_1_data()
+                .get_color(object_pixel.color)
+        }
+    }
 
-        for oam_index in self.scanline_object_id_buffer {
-            if oam_index != 0xFF {
-                object_attributes.push(io.oam.get_object_attribute(oam_index));
+    // On DMG objects are fetched in priority order (lower X, then lower OAM index), so object
+    // pixels already in the FIFO win. CGB priority only depends on the OAM index
+    fn set_object_pixel(
+        &mut self,
+        object_pixel: ObjectPixel,
+        fifo_index: usize,
+        is_oam_index_priority: bool,
+    ) {
+        while self.object_fifo.len() <= fifo_index {
+            self.object_fifo.push_back(ObjectPixel::transparent());
+        }
+
+        let current_pixel = self.object_fifo[fifo_index];
+        let is_higher_priority = is_oam_index_priority
+            && object_pixel.color != 0
+            && object_pixel.oam_index < current_pixel.oam_index;
+
+        if current_pixel.color == 0 || is_higher_priority {
+            self.object_fifo[fifo_index] = object_pixel;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PPU;
+    use crate::io::io::IO;
+
+    // Tiles 1 to 3 are filled with colors 1 to 3, and every palette maps color N to shade N, or
+    // to the RGB555 value N on CGB, so the color drawn can be read back from the screen
+    fn setup_io(cgb_mode: bool) -> IO {
+        let mut io = IO::default();
+        io.set_cgb_mode(cgb_mode);
+
+        for color in 1..4 {
+            let low = if color & 0x01 != 0 { 0xFF } else { 0x00 };
+            let high = if color & 0x02 != 0 { 0xFF } else { 0x00 };
+            fill_tile(&mut io, color, low, high);
+        }
+
+        io.write_u8(0xFF47, 0xE4);
+        io.write_u8(0xFF48, 0xE4);
+        io.write_u8(0xFF49, 0xE4);
+
+        if cgb_mode {
+            for (index_address, data_address) in [(0xFF68, 0xFF69), (0xFF6A, 0xFF6B)] {
+                io.write_u8(index_address, 0x80);
+                for color in 0..32 {
+                    io.write_u8(data_address, color % 4);
+                    io.write_u8(data_address, 0x00);
+                }
             }
         }
 
-        object_attributes.sort_by(|a, b| a.x.cmp(&b.x));
+        io
+    }
+
+    fn fill_tile(io: &mut IO, tile: u16, low: u8, high: u8) {
+        for row in 0..8 {
+            io.vram.write_u8(0x8000 + tile * 16 + row * 2, low);
+            io.vram.write_u8(0x8000 + tile * 16 + row * 2 + 1, high);
+        }
+    }
+
+    // Objects are placed on the first scanline
+    fn set_object(io: &mut IO, oam_index: usize, x: u8, tile: u8) {
+        io.oam.oam[oam_index * 4..oam_index * 4 + 4].copy_from_slice(&[16, x, tile, 0x00]);
+    }
 
-        object_attributes
+    fn start_scanline(ppu: &mut PPU, io: &mut IO, line: u8) {
+        io.lcd.lcd_y_coordinate = line;
+        ppu.update_scanline_object_id_buffer(io);
+        ppu.update_window_y_trigger(io);
+        ppu.start_pixel_transfer(io, 0);
     }
 
-    fn get_object_tiles(&self, io: &IO, object_attribute: &ObjectAttribute) -> (Tile, Tile) {
-        let object_tile_lower = io.vram.get_tile(
-            0x8000,
-            if io.lcd.is_8_height() {
-                object_attribute.tile_number
-            } else {
-                object_attribute.tile_number & 0xFE
-            },
-        );
-        let object_tile_higher = io
-            .vram
-            .get_tile(0x8000, object_attribute.tile_number | 0x01);
+    fn draw_scanline(io: &mut IO, line: u8) -> PPU {
+        let mut ppu = PPU::new();
 
-        (object_tile_lower, object_tile_higher)
+        start_scanline(&mut ppu, io, line);
+        ppu.finish_scanline(io);
+        ppu
     }
 
-    // TODO: Move to lcd probably
-    fn map_pixel_by_palette(&self, object_attribute: &ObjectAttribute, pixel: u8) -> u8 {
-        if object_attribute.is_obj_palette_0() {
-            self.oam_state.object_palette_0.get_object_color(pixel)
+    fn get_color(ppu: &PPU, io: &IO, x: usize) -> u8 {
+        let line = io.lcd.lcd_y_coordinate as usize;
+
+        if io.cgb_mode {
+            ppu.frame_buffer[line][x] as u8
         } else {
-            self.oam_state.object_palette_1.get_object_color(pixel)
+            ppu.shade_buffer[line][x]
+        }
+    }
+
+    #[test]
+    fn background_lines_up_after_window_is_disabled_mid_scanline() {
+        let mut io = setup_io(false);
+        let mut ppu = PPU::new();
+
+        // Background columns 0-15 are color 1 and 16-31 color 2, the window is color 3
+        for column in 0..32 {
+            let tile = if column < 16 { 1 } else { 2 };
+            io.vram.write_u8(0x9800 + column, tile);
+            io.vram.write_u8(0x9C00 + column, 3);
         }
+        io.write_u8(0xFF4A, 0); // WY
+        io.write_u8(0xFF4B, 7 + 40); // WX
+        io.write_u8(0xFF40, 0xF1); // Window on, window map at 0x9C00
+
+        start_scanline(&mut ppu, &mut io, 0);
+        ppu.sync(&io, 100);
+        io.write_u8(0xFF40, 0xD1);
+        ppu.finish_scanline(&io);
+
+        assert_eq!(get_color(&ppu, &io, 39), 1);
+        assert_eq!(get_color(&ppu, &io, 40), 3);
+        assert_eq!(get_color(&ppu, &io, 127), 1);
+        assert_eq!(get_color(&ppu, &io, 128), 2);
+        assert_eq!(get_color(&ppu, &io, 159), 2);
     }
 
-    fn set_object_pixel(&mut self, pixel: u8, pixel_offset: usize, is_bg_priority: bool) -> () {
-        let current_pixel_value =
-            self.frame_buffer[self.current_scanline as usize][pixel_offset as usize];
+    #[test]
+    fn dmg_objects_with_lower_x_win_then_lower_oam_index() {
+        let mut io = setup_io(false);
+        io.write_u8(0xFF40, 0x93);
+
+        // Object 1 covers pixels 8-15, object 0 pixels 12-19
+        set_object(&mut io, 0, 20, 1);
+        set_object(&mut io, 1, 16, 2);
+        // Objects 2 and 3 both cover pixels 32-39
+        set_object(&mut io, 2, 40, 3);
+        set_object(&mut io, 3, 40, 1);
+        let ppu = draw_scanline(&mut io, 0);
+
+        assert_eq!(get_color(&ppu, &io, 11), 2);
+        assert_eq!(get_color(&ppu, &io, 12), 2);
+        assert_eq!(get_color(&ppu, &io, 16), 1);
+        assert_eq!(get_color(&ppu, &io, 32), 3);
+        assert_eq!(get_color(&ppu, &io, 39), 3);
+    }
 
-        let has_priority = !is_bg_priority || current_pixel_value == 0;
+    #[test]
+    fn cgb_objects_with_lower_oam_index_win_unless_opri_is_set() {
+        for (object_priority_mode, overlap_color) in [(0x00, 1), (0x01, 2)] {
+            let mut io = setup_io(true);
+            io.write_u8(0xFF40, 0x93);
+            io.write_u8(0xFF6C, object_priority_mode);
 
-        if pixel != 4 && has_priority {
-            self.frame_buffer[self.current_scanline as usize][pixel_offset as usize] = pixel;
+            set_object(&mut io, 0, 20, 1);
+            set_object(&mut io, 1, 16, 2);
+            let ppu = draw_scanline(&mut io, 0);
+
+            assert_eq!(get_color(&ppu, &io, 11), 2);
+            assert_eq!(get_color(&ppu, &io, 12), overlap_color);
+            assert_eq!(get_color(&ppu, &io, 16), 1);
         }
     }
+
+    #[test]
+    fn objects_outside_of_the_screen_count_towards_the_object_limit() {
+        for (hidden_objects, is_last_drawn) in [(9, true), (10, false)] {
+            let mut io = setup_io(false);
+            io.write_u8(0xFF40, 0x93);
+
+            for oam_index in 0..hidden_objects {
+                let x = if oam_index % 2 == 0 { 0 } else { 168 };
+                set_object(&mut io, oam_index, x, 1);
+            }
+            set_object(&mut io, hidden_objects, 40, 2);
+            let ppu = draw_scanline(&mut io, 0);
+
+            let last_color = if is_last_drawn { 2 } else { 0 };
+            assert_eq!(get_color(&ppu, &io, 32), last_color);
+            assert!((0..160).all(|x| x / 8 == 4 || get_color(&ppu, &io, x) == 0));
+        }
+    }
+
+    #[test]
+    fn tall_objects_ignore_bit_0_of_the_tile_number() {
+        let mut io = setup_io(false);
+        io.write_u8(0xFF40, 0x97);
+
+        fill_tile(&mut io, 4, 0xFF, 0x00);
+        fill_tile(&mut io, 5, 0x00, 0xFF);
+        set_object(&mut io, 0, 16, 5);
+
+        let ppu = draw_scanline(&mut io, 0);
+        assert_eq!(get_color(&ppu, &io, 8), 1);
+        let ppu = draw_scanline(&mut io, 8);
+        assert_eq!(get_color(&ppu, &io, 8), 2);
+    }
 }
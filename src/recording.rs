@@ -0,0 +1,197 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::io::sound::{StereoSample, SAMPLE_RATE};
+
+const CHANNEL_NAMES: [&str; 4] = ["pulse1", "pulse2", "wave", "noise"];
+const HEADER_SIZE: u32 = 44;
+
+// 16 bit PCM WAV file. The sizes in the header are filled in once it is finished
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, channels: u16, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(HEADER_SIZE - 8)?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_u32::<LittleEndian>(16)?;
+        file.write_u16::<LittleEndian>(1)?; // PCM
+        file.write_u16::<LittleEndian>(channels)?;
+        file.write_u32::<LittleEndian>(sample_rate)?;
+        file.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        file.write_u16::<LittleEndian>(block_align)?;
+        file.write_u16::<LittleEndian>(16)?;
+        file.write_all(b"data")?;
+        file.write_u32::<LittleEndian>(0)?;
+
+        Ok(WavWriter { file, data_size: 0 })
+    }
+
+    // Interleaved samples from -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let sample = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+            self.file.write_i16::<LittleEndian>(sample)?;
+        }
+
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_u32::<LittleEndian>(HEADER_SIZE - 8 + self.data_size)?;
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_u32::<LittleEndian>(self.data_size)?;
+        self.file.flush()
+    }
+}
+
+// Records the mixed output, and optionally each channel to its own mono stem next to it, e.g.
+// out-pulse1.wav for out.wav
+pub struct AudioRecorder {
+    pub path: String,
+    mix: WavWriter,
+    channels: Option<Vec<WavWriter>>,
+}
+
+impl AudioRecorder {
+    pub fn start(path: &str, with_channels: bool) -> io::Result<AudioRecorder> {
+        let mix = WavWriter::create(path, 2, SAMPLE_RATE as u32)?;
+        let channels = if with_channels {
+            let stem = path.strip_suffix(".wav").unwrap_or(path);
+            let channels = CHANNEL_NAMES
+                .iter()
+                .map(|name| {
+                    WavWriter::create(&format!("{}-{}.wav", stem, name), 1, SAMPLE_RATE as u32)
+                })
+                .collect::<io::Result<Vec<WavWriter>>>()?;
+            Some(channels)
+        } else {
+            None
+        };
+
+        Ok(AudioRecorder {
+            path: String::from(path),
+            mix,
+            channels,
+        })
+    }
+
+    pub fn write_frame(
+        &mut self,
+        samples: &[StereoSample],
+        channel_samples: &[Vec<f32>; 4],
+    ) -> io::Result<()> {
+        self.mix.write_samples(samples.as_flattened())?;
+
+        if let Some(channels) = &mut self.channels {
+            for (channel, samples) in channels.iter_mut().zip(channel_samples) {
+                channel.write_samples(samples)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+
+        for channel in self.channels.into_iter().flatten() {
+            channel.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn start_recording(path: &str, record_channels: bool) -> Option<AudioRecorder> {
+    match AudioRecorder::start(path, record_channels) {
+        Ok(recorder) => {
+            println!("Recording audio to {}", path);
+            Some(recorder)
+        }
+        Err(error) => {
+            println!("[Recording] Couldn't create {}: {}", path, error);
+            None
+        }
+    }
+}
+
+// Writes the samples taken from the bus over the last frame. Recording stops if the file can't be
+// written, finishing it so what was recorded so far stays playable
+pub fn record_frame(
+    recorder: &mut Option<AudioRecorder>,
+    samples: &[StereoSample],
+    channel_samples: &[Vec<f32>; 4],
+) {
+    if let Some(active_recorder) = recorder {
+        if let Err(error) = active_recorder.write_frame(samples, channel_samples) {
+            println!("[Recording] {}", error);
+
+            if let Some(failed_recorder) = recorder.take() {
+                finish_recording(failed_recorder);
+            }
+        }
+    }
+}
+
+pub fn finish_recording(recorder: AudioRecorder) {
+    let path = recorder.path.clone();
+
+    match recorder.finish() {
+        Ok(()) => println!("Recorded audio to {}", path),
+        Err(error) => println!("[Recording] {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{finish_recording, record_frame, start_recording, CHANNEL_NAMES};
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn finished_recording_has_the_sizes_in_its_header() {
+        let path = env::temp_dir().join(format!("rustboy2-recording-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let stem = path.strip_suffix(".wav").unwrap();
+
+        let mut recorder = start_recording(path, true);
+        for _ in 0..3 {
+            let channel_samples = std::array::from_fn(|_| vec![0.25; 100]);
+            record_frame(&mut recorder, &[[0.5, -0.5]; 100], &channel_samples);
+        }
+        finish_recording(recorder.unwrap());
+
+        // 3 frames of 100 stereo samples, 2 bytes each, and 100 mono samples for each stem
+        let mix = fs::read(path).unwrap();
+        assert_eq!(mix.len(), 44 + 1200);
+        assert_eq!(read_u32(&mix, 4), 36 + 1200);
+        assert_eq!(read_u32(&mix, 40), 1200);
+        fs::remove_file(path).unwrap();
+
+        for name in CHANNEL_NAMES {
+            let channel_path = format!("{}-{}.wav", stem, name);
+            let channel = fs::read(&channel_path).unwrap();
+            assert_eq!(read_u32(&channel, 4), 36 + 600);
+            assert_eq!(read_u32(&channel, 40), 600);
+            fs::remove_file(channel_path).unwrap();
+        }
+    }
+}
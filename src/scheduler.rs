@@ -0,0 +1,75 @@
+// Events are timestamped in T-cycles, the same timeline as CPU::cycles
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EventKind {
+    PPUModeChange,
+    TimerOverflow,
+    SerialBit,
+    OamDmaStep,
+    APUFrameSequencer,
+}
+
+const EVENT_KIND_COUNT: usize = 5;
+
+const EVENT_KINDS: [EventKind; EVENT_KIND_COUNT] = [
+    EventKind::PPUModeChange,
+    EventKind::TimerOverflow,
+    EventKind::SerialBit,
+    EventKind::OamDmaStep,
+    EventKind::APUFrameSequencer,
+];
+
+const UNSCHEDULED: u64 = u64::MAX;
+
+// Each kind of event can only be queued once, so rescheduling an event replaces it
+pub struct Scheduler {
+    event_times: [u64; EVENT_KIND_COUNT],
+    next_event_time: u64,
+}
+
+impl Scheduler {
+    pub fn default() -> Scheduler {
+        Scheduler {
+            event_times: [UNSCHEDULED; EVENT_KIND_COUNT],
+            next_event_time: UNSCHEDULED,
+        }
+    }
+
+    pub fn schedule(&mut self, event_kind: EventKind, time: u64) {
+        self.event_times[event_kind as usize] = time;
+        self.update_next_event_time();
+    }
+
+    pub fn cancel(&mut self, event_kind: EventKind) {
+        self.event_times[event_kind as usize] = UNSCHEDULED;
+        self.update_next_event_time();
+    }
+
+    pub fn next_event_time(&self) -> u64 {
+        self.next_event_time
+    }
+
+    // Removes and returns the earliest event due at or before the given time, along with the
+    // time it was scheduled for so handlers can schedule follow up events without drifting
+    pub fn pop_due_event(&mut self, now: u64) -> Option<(EventKind, u64)> {
+        if self.next_event_time > now {
+            return None;
+        }
+
+        let (index, time) = self
+            .event_times
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, time)| **time)
+            .map(|(index, time)| (index, *time))?;
+
+        self.event_times[index] = UNSCHEDULED;
+        self.update_next_event_time();
+
+        Some((EVENT_KINDS[index], time))
+    }
+
+    fn update_next_event_time(&mut self) {
+        self.next_event_time = *self.event_times.iter().min().unwrap_or(&UNSCHEDULED);
+    }
+}
@@ -1,7 +1,8 @@
 pub struct WRam {
     bank0: [u8; 0x1000],
     bankn: [[u8; 0x1000]; 7],
-    ram_bank: u8, // Always 1 for non-CGB
+    ram_bank: u8, // Always 1 for non-CGB, SVBK (0xFF70) on CGB where 0 also selects bank 1
+    pub cgb_mode: bool,
 }
 
 impl WRam {
@@ -10,6 +11,7 @@ impl WRam {
             bank0: [0; 0x1000],
             bankn: [[0; 0x1000]; 7],
             ram_bank: 1,
+            cgb_mode: false,
         }
     }
 
@@ -26,6 +28,8 @@ impl WRam {
             }
             0xE000..=0xEFFF => self.bank0[(address - 0xE000) as usize] = value,
             0xF000..=0xFDFF => self.bankn[ram_bank as usize][(address - 0xF000) as usize] = value,
+            0xFF70 if self.cgb_mode => self.ram_bank = value & 0x07,
+            0xFF70 => {} // DMG has no SVBK
             _ => panic!("Invalid WRam Write address: 0x{:04X}", address),
         }
     }
@@ -44,20 +48,9 @@ impl WRam {
             }
             0xE000..=0xEFFF => self.bank0[(address - 0xE000) as usize],
             0xF000..=0xFDFF => self.bankn[ram_bank as usize][(address - 0xF000) as usize],
+            0xFF70 if self.cgb_mode => 0xF8 | self.ram_bank,
+            0xFF70 => 0xFF, // DMG has no SVBK
             _ => panic!("Invalid WRam Read address: 0x{:04X}", address),
         }
     }
-
-    pub fn read_u16(&self, address: u16) -> u16 {
-        let low_byte = self.read_u8(address) as u16;
-        let high_byte = self.read_u8(address + 1) as u16;
-
-        // println!(
-        //     "WRam Read: 0x{:04X} -- 0x{:04X}",
-        //     address,
-        //     (high_byte << 8) | low_byte
-        // );
-
-        (high_byte << 8) | low_byte
-    }
 }